@@ -0,0 +1,157 @@
+use crate::metadata::format_date;
+use crate::refresh_cookie::{create_client, create_headers, read_cookie_or_not};
+use anyhow::{anyhow, Result};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+/// 未登录时可获取的清晰度
+const GUEST_QUALITIES: [&str; 2] = ["480P", "360P"];
+/// 需要大会员的清晰度
+const VIP_QUALITIES: [&str; 4] = ["HDR", "4K", "1080P+", "1080P60"];
+
+/// cookie对应的账号，来自nav接口
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Account {
+    /// cookie是否有效
+    pub is_login: bool,
+    pub mid: u64,
+    pub uname: String,
+    /// 0 无，1 月度大会员，2 年度及以上大会员
+    pub vip_type: i64,
+    /// 1 为有效
+    pub vip_status: i64,
+    /// 大会员到期时间，毫秒时间戳
+    pub vip_due_date: i64,
+    /// 大会员名称，如 年度大会员
+    pub vip_label: String,
+}
+
+impl Account {
+    pub fn is_vip(&self) -> bool {
+        self.is_login && self.vip_status == 1 && self.vip_type > 0
+    }
+
+    /// 大会员名称和到期日期，不是大会员时为空
+    pub fn vip_text(&self) -> String {
+        if !self.is_vip() {
+            return String::new();
+        }
+        let label = if self.vip_label.is_empty() {
+            "大会员"
+        } else {
+            &self.vip_label
+        };
+        format!("{} (到期 {})", label, format_date(self.vip_due_date / 1000))
+    }
+
+    /// 一行的账号状态，用于界面标题栏
+    pub fn describe(&self) -> String {
+        if !self.is_login {
+            return "未登录".to_string();
+        }
+        if self.is_vip() {
+            format!("{} | {}", self.uname, self.vip_text())
+        } else {
+            self.uname.clone()
+        }
+    }
+
+    /// 账号无法获取所选清晰度时的提示
+    pub fn quality_warning(&self, rsl: &str) -> Option<String> {
+        if !self.is_login && !GUEST_QUALITIES.contains(&rsl) {
+            Some(format!("未登录或登录已失效，{}不可用，最高为480P", rsl))
+        } else if !self.is_vip() && VIP_QUALITIES.contains(&rsl) {
+            Some(format!("{}需要大会员，当前账号最高为1080P", rsl))
+        } else {
+            None
+        }
+    }
+}
+
+/// 解析nav接口的返回，未登录(-101)不是错误
+fn parse_nav(json: &Value) -> Result<Account> {
+    let code = json["code"].as_i64().unwrap_or(-1);
+    let data = &json["data"];
+    if code == -101 {
+        return Ok(Account::default());
+    }
+    if code != 0 {
+        return Err(anyhow!(
+            "nav failed with code {}: {}",
+            code,
+            json["message"]
+        ));
+    }
+    if !data["isLogin"].as_bool().unwrap_or(false) {
+        return Ok(Account::default());
+    }
+    Ok(Account {
+        is_login: true,
+        mid: data["mid"].as_u64().unwrap_or(0),
+        uname: data["uname"].as_str().unwrap_or("").to_string(),
+        vip_type: data["vipType"].as_i64().unwrap_or(0),
+        vip_status: data["vipStatus"].as_i64().unwrap_or(0),
+        vip_due_date: data["vipDueDate"].as_i64().unwrap_or(0),
+        vip_label: data["vip_label"]["text"].as_str().unwrap_or("").to_string(),
+    })
+}
+
+/// 查询客户端cookie对应的账号
+pub async fn fetch_account(client: &Client) -> Result<Account> {
+    let url = "https://api.bilibili.com/x/web-interface/nav";
+    let json: Value = client
+        .get(url)
+        .headers(create_headers())
+        .send()
+        .await?
+        .json()
+        .await?;
+    parse_nav(&json)
+}
+
+/// 查询cookie文件对应的账号，文件不存在时为未登录
+pub async fn load_account(path: &Path) -> Result<Account> {
+    let cookie = read_cookie_or_not(path).await?;
+    if cookie.SESSDATA.is_empty() {
+        return Ok(Account::default());
+    }
+    fetch_account(&create_client(&cookie)?).await
+}
+
+#[test]
+fn test_account() {
+    let json: Value = serde_json::from_str(
+        r#"{"code":0,"message":"0","ttl":1,"data":{"isLogin":true,"mid":12345,"uname":"测试","vipType":2,"vipStatus":1,"vipDueDate":1735660800000,"vip_label":{"text":"年度大会员"}}}"#,
+    )
+    .unwrap();
+    let account = parse_nav(&json).unwrap();
+    assert!(account.is_vip());
+    assert_eq!(account.mid, 12345);
+    assert_eq!(account.describe(), "测试 | 年度大会员 (到期 2025-01-01)");
+    assert_eq!(account.quality_warning("4K"), None);
+
+    let json: Value = serde_json::from_str(
+        r#"{"code":0,"data":{"isLogin":true,"mid":1,"uname":"a","vipType":1,"vipStatus":0,"vipDueDate":0,"vip_label":{"text":""}}}"#,
+    )
+    .unwrap();
+    let account = parse_nav(&json).unwrap();
+    assert!(!account.is_vip());
+    assert_eq!(account.describe(), "a");
+    assert!(account.quality_warning("1080P+").is_some());
+    assert_eq!(account.quality_warning("1080P"), None);
+
+    let json: Value =
+        serde_json::from_str(r#"{"code":-101,"message":"账号未登录","data":{"isLogin":false}}"#)
+            .unwrap();
+    let account = parse_nav(&json).unwrap();
+    assert!(!account.is_login);
+    assert_eq!(account.describe(), "未登录");
+    assert!(account.quality_warning("720P").is_some());
+    assert_eq!(account.quality_warning("480P"), None);
+    assert!(
+        parse_nav(&serde_json::from_str(r#"{"code":-412,"message":"请求被拦截"}"#).unwrap())
+            .is_err()
+    );
+}
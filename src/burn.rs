@@ -0,0 +1,253 @@
+use crate::config::{Config, Container};
+use crate::ffmpeg;
+use crate::subtitle::SubtitleTrack;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// 压制进画面的内容
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BurnSource {
+    /// 不压制
+    #[default]
+    None,
+    /// 生成的弹幕ASS
+    Danmaku,
+    /// CC字幕，优先使用默认字幕语言
+    Subtitle,
+}
+
+named_enum!(BurnSource {
+    None => "none",
+    Danmaku => "danmaku",
+    Subtitle => "subs",
+});
+
+/// 重新编码使用的视频编码器
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BurnEncoder {
+    #[default]
+    X264,
+    X265,
+    /// NVIDIA硬件编码
+    Nvenc,
+    /// Intel硬件编码
+    Qsv,
+    /// macOS硬件编码
+    VideoToolbox,
+}
+
+named_enum!(BurnEncoder {
+    X264 => "x264",
+    X265 => "x265",
+    Nvenc => "nvenc",
+    Qsv => "qsv",
+    VideoToolbox => "videotoolbox",
+});
+
+impl BurnEncoder {
+    /// ffmpeg中的编码器名
+    fn codec(&self) -> &'static str {
+        match self {
+            BurnEncoder::X264 => "libx264",
+            BurnEncoder::X265 => "libx265",
+            BurnEncoder::Nvenc => "h264_nvenc",
+            BurnEncoder::Qsv => "h264_qsv",
+            BurnEncoder::VideoToolbox => "h264_videotoolbox",
+        }
+    }
+}
+
+/// 压制的速度和画质
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BurnQuality {
+    Fast,
+    #[default]
+    Balanced,
+    High,
+}
+
+named_enum!(BurnQuality {
+    Fast => "fast",
+    Balanced => "balanced",
+    High => "high",
+});
+
+/// 编码器对应的预设和质量参数，各编码器的参数名不同
+fn encoder_args(encoder: BurnEncoder, quality: BurnQuality) -> Vec<String> {
+    let index = match quality {
+        BurnQuality::Fast => 0,
+        BurnQuality::Balanced => 1,
+        BurnQuality::High => 2,
+    };
+    let (preset, quality): ([&str; 3], (&str, [&str; 3])) = match encoder {
+        BurnEncoder::X264 | BurnEncoder::X265 => {
+            (["veryfast", "medium", "slow"], ("-crf", ["26", "23", "20"]))
+        }
+        BurnEncoder::Nvenc => (["p2", "p4", "p7"], ("-cq", ["28", "23", "19"])),
+        BurnEncoder::Qsv => (
+            ["veryfast", "medium", "veryslow"],
+            ("-global_quality", ["28", "23", "20"]),
+        ),
+        // videotoolbox没有预设，-q:v越大画质越好
+        BurnEncoder::VideoToolbox => {
+            return ["-c:v", encoder.codec(), "-q:v", ["50", "65", "80"][index]]
+                .map(String::from)
+                .to_vec()
+        }
+    };
+    [
+        "-c:v",
+        encoder.codec(),
+        "-preset",
+        preset[index],
+        quality.0,
+        quality.1[index],
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// 按设置选择要压制的文件：弹幕ASS，或默认语言(没有时为第一条)的字幕
+pub fn burn_source(
+    name: &str,
+    subtitles: &[(SubtitleTrack, String)],
+    config: &Config,
+) -> Option<String> {
+    match config.burn {
+        BurnSource::None => None,
+        BurnSource::Danmaku => {
+            let path = format!("./download/{}.danmaku.ass", name);
+            Path::new(&path).exists().then_some(path)
+        }
+        BurnSource::Subtitle => subtitles
+            .iter()
+            .find(|(t, _)| t.lan.eq_ignore_ascii_case(config.default_subtitle.trim()))
+            .or(subtitles.first())
+            .map(|(_, path)| path.clone()),
+    }
+}
+
+/// subtitles滤镜中的文件名需要转义两次：先按选项值转义，再按滤镜图转义
+fn escape_filter_path(path: &str) -> String {
+    let mut value = String::new();
+    for c in path.chars() {
+        if matches!(c, '\\' | ':' | '\'') {
+            value.push('\\');
+        }
+        value.push(c);
+    }
+    let mut graph = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+        graph.push(c);
+    }
+    graph
+}
+
+/// 将字幕压制进合并后的视频，替换原文件；音频、字幕轨、章节和封面直接复制
+pub async fn burn_in(
+    name: &str,
+    source: &str,
+    has_cover: bool,
+    duration: u64,
+    config: &Config,
+) -> Result<()> {
+    let container = config.container;
+    if container == Container::Raw {
+        return Ok(());
+    }
+    let output = container.output_path(name);
+    let temp = format!("./download/{}.burn.{}", name, container.name());
+    let filter = format!("[0:v:0]subtitles={}[v]", escape_filter_path(source));
+    let mut args: Vec<String> = [
+        "-i",
+        output.as_str(),
+        "-filter_complex",
+        filter.as_str(),
+        "-map",
+        "[v]",
+        "-map",
+        "0:a",
+        "-map",
+        "0:s?",
+        "-map_metadata",
+        "0",
+        "-map_chapters",
+        "0",
+    ]
+    .map(String::from)
+    .to_vec();
+    if has_cover && container == Container::Mp4 {
+        args.extend(["-map", "0:v:1"].map(String::from));
+    }
+    if container == Container::Mkv {
+        args.extend(["-map", "0:t?"].map(String::from));
+    }
+    args.extend(encoder_args(config.burn_encoder, config.burn_quality));
+    // 封面流保持原样
+    if has_cover && container == Container::Mp4 {
+        args.extend(["-c:v:1", "copy", "-disposition:v:1", "attached_pic"].map(String::from));
+    }
+    args.extend(["-c:a", "copy", "-c:s", "copy", "-y"].map(String::from));
+    if container == Container::Mp4 {
+        args.extend(["-movflags", "+faststart"].map(String::from));
+    }
+    args.extend([temp.as_str(), "-hide_banner", "-loglevel", "error"].map(String::from));
+    let stage = format!("压制 {}", name);
+    let result = ffmpeg::run_ffmpeg(&config.ffmpeg_path, &args, &stage, duration).await;
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Failed to burn {} into {}", source, name));
+    }
+    std::fs::rename(&temp, &output)?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[test]
+fn test_burn() {
+    assert_eq!(
+        escape_filter_path("./download/a'b:c [1].ass"),
+        "./download/a\\\\\\'b\\\\:c \\[1\\].ass"
+    );
+    assert_eq!(
+        encoder_args(BurnEncoder::Nvenc, BurnQuality::High),
+        ["-c:v", "h264_nvenc", "-preset", "p7", "-cq", "19"]
+    );
+    assert_eq!(
+        encoder_args(BurnEncoder::VideoToolbox, BurnQuality::Fast),
+        ["-c:v", "h264_videotoolbox", "-q:v", "50"]
+    );
+    assert_eq!(BurnSource::from_name("SUBS"), Some(BurnSource::Subtitle));
+
+    let track = |lan: &str| SubtitleTrack {
+        lan: lan.to_string(),
+        lan_doc: String::new(),
+        url: String::new(),
+        ai: false,
+    };
+    let subtitles = [
+        (track("ai-zh"), "a.ai-zh.srt".to_string()),
+        (track("en-US"), "a.en-US.srt".to_string()),
+    ];
+    let mut config = Config {
+        burn: BurnSource::Subtitle,
+        ..Default::default()
+    };
+    assert_eq!(
+        burn_source("a", &subtitles, &config).as_deref(),
+        Some("a.ai-zh.srt")
+    );
+    config.default_subtitle = "en-us".to_string();
+    assert_eq!(
+        burn_source("a", &subtitles, &config).as_deref(),
+        Some("a.en-US.srt")
+    );
+    assert_eq!(burn_source("a", &[], &config), None);
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// 视频章节(看点)，时间单位为秒
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// 从播放器信息中获取看点
+pub fn parse_view_points(player: &Value) -> Vec<Chapter> {
+    player["data"]["view_points"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|v| Chapter {
+            title: v["content"].as_str().unwrap_or("").to_string(),
+            start: v["from"].as_u64().unwrap_or(0),
+            end: v["to"].as_u64().unwrap_or(0),
+        })
+        .filter(|c| c.end > c.start)
+        .collect()
+}
+
+fn escape_ffmetadata(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// 生成ffmpeg的元数据文件，用于 -map_chapters
+pub fn to_ffmetadata(chapters: &[Chapter]) -> String {
+    let mut out = String::from(";FFMETADATA1\n");
+    for c in chapters {
+        out.push_str("[CHAPTER]\nTIMEBASE=1/1000\n");
+        out.push_str(&format!("START={}\nEND={}\n", c.start * 1000, c.end * 1000));
+        out.push_str(&format!("title={}\n", escape_ffmetadata(&c.title)));
+    }
+    out
+}
+
+/// 生成OGM格式的章节文本，多数播放器和mkvmerge可导入
+pub fn to_ogm(chapters: &[Chapter]) -> String {
+    let mut out = String::new();
+    for (i, c) in chapters.iter().enumerate() {
+        let (h, m, s) = (c.start / 3600, c.start / 60 % 60, c.start % 60);
+        out.push_str(&format!(
+            "CHAPTER{:02}={:02}:{:02}:{:02}.000\n",
+            i + 1,
+            h,
+            m,
+            s
+        ));
+        out.push_str(&format!("CHAPTER{:02}NAME={}\n", i + 1, c.title));
+    }
+    out
+}
+
+#[test]
+fn test_chapters() {
+    let player: Value = serde_json::from_str(
+        r#"{"data": {"view_points": [
+            {"content": "开场", "from": 0, "to": 75},
+            {"content": "a=b", "from": 75, "to": 3700}
+        ]}}"#,
+    )
+    .unwrap();
+    let chapters = parse_view_points(&player);
+    assert_eq!(chapters.len(), 2);
+    assert_eq!(
+        to_ffmetadata(&chapters[1..]),
+        ";FFMETADATA1\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=75000\nEND=3700000\ntitle=a\\=b\n"
+    );
+    assert_eq!(
+        to_ogm(&chapters),
+        "CHAPTER01=00:00:00.000\nCHAPTER01NAME=开场\nCHAPTER02=00:01:15.000\nCHAPTER02NAME=a=b\n"
+    );
+}
@@ -0,0 +1,284 @@
+use crate::account;
+use crate::burn::{BurnEncoder, BurnQuality, BurnSource};
+use crate::config::{self, Container};
+use crate::danmaku::DanmakuProfile;
+use crate::init_;
+use crate::inspect::print_info;
+use crate::profile;
+use crate::qrcode_login::{login_qrcode, QrcodeDisplay};
+use crate::refresh_cookie;
+use crate::subtitle::SubtitleFormat;
+use anyhow::Result;
+use reqwest::cookie::Jar;
+use std::sync::Arc;
+
+fn print_usage() {
+    println!("用法:");
+    println!("  Bili_download                      启动图形界面");
+    println!("  Bili_download login [--png]         扫码登录，默认在终端中显示二维码");
+    println!("  Bili_download whoami [--json]       查看当前账号、大会员和登录状态");
+    println!("  Bili_download profile list          列出全部账号，*为当前账号");
+    println!("  Bili_download profile use <名称>     设置默认使用的账号");
+    println!("  Bili_download profile remove <名称>  删除账号的cookie");
+    println!("  Bili_download inspect <url> [--json]  查看可下载的格式");
+    println!("  Bili_download download <url> [选项]   下载视频");
+    println!("    --rsl <分辨率>    HDR/4K/1080P+/1080P60/1080P/720P/480P/360P，默认4K");
+    println!("    --audio-only      只下载音频，输出带标签和封面的m4a/flac");
+    println!("    --container <格式> mp4/mkv/raw，默认读取config.json");
+    println!("    --ffmpeg          mp4也使用ffmpeg合并(默认使用内置合并)");
+    println!("    --stream          边下载边合并mp4，不生成m4s中间文件");
+    println!("    --ffmpeg-path <路径> ffmpeg可执行文件路径");
+    println!("    --no-cover        不保存也不嵌入封面");
+    println!("    --cover-original  保存原图封面");
+    println!("    --subs            下载CC字幕(含AI字幕)");
+    println!("    --sub-langs <语言> 只下载这些语言，如 zh-CN,ai-zh");
+    println!("    --sub-format <格式> srt/ass/vtt，默认srt");
+    println!("    --embed-subs      将字幕嵌入为字幕轨(mp4需要ffmpeg)");
+    println!("    --default-sub <语言> 默认显示的字幕，如 zh-CN");
+    println!("    --danmaku         下载弹幕并转换为ASS字幕");
+    println!("    --danmaku-json    同时另存原始弹幕JSON");
+    println!("    --danmaku-profile <文件> 使用文件中的弹幕过滤和样式规则");
+    println!("    --burn <内容>     danmaku/subs，合并后将弹幕或字幕压制进画面(需要ffmpeg)");
+    println!("    --burn-encoder <编码器> x264/x265/nvenc/qsv/videotoolbox，默认x264");
+    println!("    --burn-quality <画质> fast/balanced/high，默认balanced");
+    println!("    --retries <次数>  下载或校验失败后的重试次数，默认2");
+    println!("    --no-chapters     不写入看点章节");
+    println!("    --export-chapters 另存章节文本(.chapters.txt)");
+    println!("  以上命令都可以加 --profile <名称> 使用指定账号，login时不存在则新建");
+}
+
+/// 命令行入口
+pub async fn run(args: &[String]) -> Result<()> {
+    if let Some(name) = option_value(args, "--profile") {
+        profile::check_name(name)?;
+        profile::set_active(name);
+    }
+    match args[0].as_str() {
+        "inspect" => inspect(&args[1..]).await,
+        "download" => download(&args[1..]).await,
+        "login" => login(&args[1..]).await,
+        "whoami" => whoami(&args[1..]).await,
+        "profile" => manage_profile(&args[1..]).await,
+        _ => {
+            print_usage();
+            Ok(())
+        }
+    }
+}
+
+/// 取出选项的值，如 --rsl 4K
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// 第一个不属于选项的参数
+fn positional<'a>(args: &'a [String], options: &[&str]) -> Option<&'a String> {
+    let mut i = 0;
+    while i < args.len() {
+        if options.contains(&args[i].as_str()) {
+            i += 2;
+        } else if args[i].starts_with("--") {
+            i += 1;
+        } else {
+            return Some(&args[i]);
+        }
+    }
+    None
+}
+
+/// 下载视频
+async fn download(args: &[String]) -> Result<()> {
+    let url = positional(
+        args,
+        &[
+            "--rsl",
+            "--container",
+            "--ffmpeg-path",
+            "--retries",
+            "--sub-langs",
+            "--sub-format",
+            "--default-sub",
+            "--danmaku-profile",
+            "--burn",
+            "--burn-encoder",
+            "--burn-quality",
+            "--profile",
+        ],
+    )
+    .ok_or_else(|| anyhow::anyhow!("Missing url"))?;
+    let rsl = option_value(args, "--rsl").unwrap_or("4K");
+    let mut config = config::load_config();
+    if args.iter().any(|a| a == "--audio-only") {
+        config.audio_only = true;
+    }
+    if args.iter().any(|a| a == "--ffmpeg") {
+        config.use_ffmpeg = true;
+    }
+    if args.iter().any(|a| a == "--stream") {
+        config.stream_mux = true;
+    }
+    if args.iter().any(|a| a == "--no-cover") {
+        config.save_cover = false;
+        config.embed_cover = false;
+    }
+    if args.iter().any(|a| a == "--cover-original") {
+        config.cover_original = true;
+    }
+    if args.iter().any(|a| a == "--no-chapters") {
+        config.chapters = false;
+    }
+    if args.iter().any(|a| a == "--export-chapters") {
+        config.export_chapters = true;
+    }
+    if args.iter().any(|a| a == "--subs") {
+        config.subtitles = true;
+    }
+    if let Some(langs) = option_value(args, "--sub-langs") {
+        config.subtitles = true;
+        config.subtitle_langs = langs.split(',').map(|l| l.trim().to_string()).collect();
+    }
+    if let Some(name) = option_value(args, "--sub-format") {
+        config.subtitles = true;
+        config.subtitle_format = SubtitleFormat::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown subtitle format: {}", name))?;
+    }
+    if args.iter().any(|a| a == "--embed-subs") {
+        config.subtitles = true;
+        config.embed_subtitles = true;
+    }
+    if let Some(lang) = option_value(args, "--default-sub") {
+        config.default_subtitle = lang.to_string();
+    }
+    if args.iter().any(|a| a == "--danmaku") {
+        config.danmaku = true;
+    }
+    if args.iter().any(|a| a == "--danmaku-json") {
+        config.danmaku = true;
+        config.danmaku_json = true;
+    }
+    if let Some(path) = option_value(args, "--danmaku-profile") {
+        config.danmaku = true;
+        config.danmaku_profile = DanmakuProfile::load(path)
+            .map_err(|e| anyhow::anyhow!("Failed to load danmaku profile {}: {}", path, e))?;
+    }
+    if let Some(name) = option_value(args, "--burn") {
+        config.burn = BurnSource::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown burn source: {}", name))?;
+        match config.burn {
+            BurnSource::Danmaku => config.danmaku = true,
+            BurnSource::Subtitle => config.subtitles = true,
+            BurnSource::None => {}
+        }
+    }
+    if let Some(name) = option_value(args, "--burn-encoder") {
+        config.burn_encoder = BurnEncoder::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown encoder: {}", name))?;
+    }
+    if let Some(name) = option_value(args, "--burn-quality") {
+        config.burn_quality = BurnQuality::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown burn quality: {}", name))?;
+    }
+    if let Some(retries) = option_value(args, "--retries") {
+        config.retries = retries
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid retry count: {}", retries))?;
+    }
+    if let Some(path) = option_value(args, "--ffmpeg-path") {
+        config.ffmpeg_path = path.to_string();
+    }
+    if let Some(name) = option_value(args, "--container") {
+        config.container = Container::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown container: {}", name))?;
+    }
+    let video = init_::get_epid_season(url)?;
+    let title = init_::choose_download_method(&video, rsl, &config).await?;
+    println!("Download completed for {}", title);
+    Ok(())
+}
+
+/// 扫码登录，二维码默认打印在终端中，--png时保存为output.png
+async fn login(args: &[String]) -> Result<()> {
+    let display = if args.iter().any(|a| a == "--png") {
+        QrcodeDisplay::Png
+    } else {
+        QrcodeDisplay::Terminal
+    };
+    // 登录响应的Set-Cookie保存在jar中
+    let jar = Arc::new(Jar::default());
+    let client = refresh_cookie::client_with_jar(jar.clone())?;
+    if login_qrcode(&client, &jar, display).await {
+        println!("Login successful");
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Login failed"))
+    }
+}
+
+/// 查看load中cookie对应的账号
+async fn whoami(args: &[String]) -> Result<()> {
+    let account = account::load_account(&profile::cookie_path()).await?;
+    if args.iter().any(|a| a == "--json") {
+        println!("{}", serde_json::to_string_pretty(&account)?);
+        return Ok(());
+    }
+    if !account.is_login {
+        println!("未登录或登录已失效，请使用 Bili_download login 登录");
+        return Ok(());
+    }
+    println!("账号: {} ({})", account.uname, profile::active());
+    println!("mid: {}", account.mid);
+    if account.is_vip() {
+        println!("大会员: {}", account.vip_text());
+    } else {
+        println!("大会员: 无");
+    }
+    println!("登录状态: 有效");
+    Ok(())
+}
+
+/// 列出、切换和删除账号
+async fn manage_profile(args: &[String]) -> Result<()> {
+    let name = positional(&args[1..], &["--profile"]);
+    match (args.first().map(|a| a.as_str()), name) {
+        (Some("list"), _) => {
+            let active = profile::active();
+            for name in profile::list() {
+                let mark = if name == active { "*" } else { " " };
+                match account::load_account(&profile::path_of(&name)).await {
+                    Ok(account) => println!("{} {} {}", mark, name, account.describe()),
+                    Err(e) => println!("{} {} {}", mark, name, e),
+                }
+            }
+        }
+        (Some("use"), Some(name)) => {
+            profile::check_name(name)?;
+            let mut config = config::load_config();
+            config.profile = name.clone();
+            config::save_config(&config)?;
+            println!("默认账号: {}", name);
+        }
+        (Some("remove"), Some(name)) => {
+            profile::remove(name)?;
+            println!("已删除账号: {}", name);
+        }
+        _ => print_usage(),
+    }
+    Ok(())
+}
+
+/// 查看视频的全部格式、字幕和弹幕，不下载
+async fn inspect(args: &[String]) -> Result<()> {
+    let url = positional(args, &["--profile"]).ok_or_else(|| anyhow::anyhow!("Missing url"))?;
+    let json = args.iter().any(|a| a == "--json");
+    let video = init_::get_epid_season(url)?;
+    let info = init_::get_media_info(&video).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        print_info(&info);
+    }
+    Ok(())
+}
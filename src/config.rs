@@ -0,0 +1,175 @@
+use crate::burn::{BurnEncoder, BurnQuality, BurnSource};
+use crate::danmaku::DanmakuProfile;
+use crate::subtitle::SubtitleFormat;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// 输出容器
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Mp4,
+    /// 支持flac音频和多字幕轨
+    Mkv,
+    /// 不合并，保留原始m4s
+    Raw,
+}
+
+named_enum!(Container {
+    Mp4 => "mp4",
+    Mkv => "mkv",
+    Raw => "raw",
+});
+
+impl Container {
+    /// 输出文件路径，raw时为视频流文件
+    pub fn output_path(&self, name: &str) -> String {
+        match self {
+            Container::Raw => format!("./download/{}_video.m4s", name),
+            _ => format!("./download/{}.{}", name, self.name()),
+        }
+    }
+}
+
+/// 下载选项，默认值保存在config.json
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// 只下载音频流，输出m4a/flac
+    pub audio_only: bool,
+    pub container: Container,
+    /// mp4也使用ffmpeg合并，默认使用内置的合并
+    pub use_ffmpeg: bool,
+    /// 边下载边合并，不生成m4s中间文件，仅用于内置合并的mp4
+    pub stream_mux: bool,
+    /// ffmpeg可执行文件路径
+    pub ffmpeg_path: String,
+    /// 在视频旁保存封面
+    pub save_cover: bool,
+    /// 将封面嵌入mp4/mkv/m4a
+    pub embed_cover: bool,
+    /// 保存原图，否则保存1280宽的jpg
+    pub cover_original: bool,
+    /// 下载或校验失败后的重试次数
+    pub retries: u32,
+    /// 下载CC字幕
+    pub subtitles: bool,
+    /// 只下载这些语言的字幕，如 zh-CN、ai-zh，为空时下载全部
+    pub subtitle_langs: Vec<String>,
+    pub subtitle_format: SubtitleFormat,
+    /// 将字幕嵌入为字幕轨，mp4需要ffmpeg
+    pub embed_subtitles: bool,
+    /// 默认显示的字幕语言，为空时不设置默认字幕
+    pub default_subtitle: String,
+    /// 下载弹幕并转换为ASS
+    pub danmaku: bool,
+    /// 另存合并后的原始弹幕JSON
+    pub danmaku_json: bool,
+    /// 弹幕的过滤和样式规则
+    pub danmaku_profile: DanmakuProfile,
+    /// 合并后将弹幕或字幕压制进画面，需要重新编码
+    pub burn: BurnSource,
+    pub burn_encoder: BurnEncoder,
+    pub burn_quality: BurnQuality,
+    /// 将看点写入为章节
+    pub chapters: bool,
+    /// 另存OGM格式的章节文本
+    pub export_chapters: bool,
+    /// 使用的账号，为空时为默认账号
+    pub profile: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            audio_only: false,
+            container: Container::default(),
+            use_ffmpeg: false,
+            stream_mux: false,
+            ffmpeg_path: "ffmpeg".to_string(),
+            save_cover: true,
+            embed_cover: true,
+            cover_original: false,
+            retries: 2,
+            subtitles: false,
+            subtitle_langs: Vec::new(),
+            subtitle_format: SubtitleFormat::default(),
+            embed_subtitles: false,
+            default_subtitle: String::new(),
+            danmaku: false,
+            danmaku_json: false,
+            danmaku_profile: DanmakuProfile::default(),
+            burn: BurnSource::default(),
+            burn_encoder: BurnEncoder::default(),
+            burn_quality: BurnQuality::default(),
+            chapters: true,
+            export_chapters: false,
+            profile: String::new(),
+        }
+    }
+}
+
+impl Config {
+    /// 当前选项是否需要调用ffmpeg，仅音频时总是需要ffmpeg输出m4a/flac
+    pub fn needs_ffmpeg(&self) -> bool {
+        if self.audio_only {
+            return true;
+        }
+        match self.container {
+            Container::Raw => false,
+            Container::Mkv => true,
+            Container::Mp4 => {
+                self.use_ffmpeg
+                    || (self.subtitles && self.embed_subtitles)
+                    || self.burn != BurnSource::None
+            }
+        }
+    }
+
+    /// 当前选项下是否边下载边合并
+    pub fn streams(&self) -> bool {
+        self.stream_mux && self.container == Container::Mp4 && !self.needs_ffmpeg()
+    }
+}
+
+/// 读取配置文件，不存在或格式错误时使用默认配置
+pub fn load_config() -> Config {
+    let path = Path::new("config.json");
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            eprintln!("Error occurred: {}", e);
+            Config::default()
+        }),
+        Err(_) => Config::default(),
+    }
+}
+
+/// 保存配置文件
+pub fn save_config(config: &Config) -> Result<()> {
+    let content = serde_json::to_string_pretty(config)?;
+    std::fs::write("config.json", content)?;
+    Ok(())
+}
+
+#[test]
+fn test_container() {
+    assert_eq!(Container::from_name("MKV"), Some(Container::Mkv));
+    assert_eq!(Container::from_name("avi"), None);
+    assert_eq!(Container::Mp4.output_path("a"), "./download/a.mp4");
+    assert_eq!(Container::Raw.output_path("a"), "./download/a_video.m4s");
+    let config: Config = serde_json::from_str(r#"{"container": "mkv"}"#).unwrap();
+    assert_eq!(config.container, Container::Mkv);
+    assert!(!config.audio_only);
+    assert_eq!(config.ffmpeg_path, "ffmpeg");
+    assert!(config.needs_ffmpeg());
+    assert!(!config.streams());
+    let config: Config =
+        serde_json::from_str(r#"{"container": "raw", "audio_only": true}"#).unwrap();
+    assert!(config.needs_ffmpeg());
+    let config: Config =
+        serde_json::from_str(r#"{"stream_mux": true, "burn": "danmaku"}"#).unwrap();
+    assert!(config.needs_ffmpeg());
+    assert!(!config.streams());
+}
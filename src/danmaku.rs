@@ -0,0 +1,585 @@
+use anyhow::{anyhow, Result};
+use flate2::read::DeflateDecoder;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+/// 一条弹幕
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Danmaku {
+    /// 弹幕id，用于合并分段时去重，未知时为0
+    pub id: u64,
+    /// 出现时间(秒)
+    pub time: f64,
+    /// 1-3滚动，4底部，5顶部，6逆向，7高级，8代码
+    pub mode: u8,
+    /// 字号，默认25
+    pub size: u32,
+    /// RGB颜色
+    pub color: u32,
+    pub text: String,
+}
+
+/// seg.so每个分段的时长(秒)
+pub const SEGMENT_DURATION: u64 = 360;
+
+/// 弹幕的过滤和样式规则，保存在config.json中，也可以从单独的文件读取
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DanmakuProfile {
+    /// 包含这些关键词的弹幕不显示
+    pub block_keywords: Vec<String>,
+    /// 匹配这些正则表达式的弹幕不显示
+    pub block_regex: Vec<String>,
+    pub show_scroll: bool,
+    pub show_top: bool,
+    pub show_bottom: bool,
+    /// 高级弹幕无法转换为ASS，只影响JSON导出
+    pub show_advanced: bool,
+    /// 同屏最多显示的弹幕数，0为不限制
+    pub max_on_screen: usize,
+    /// 滚动弹幕的显示时长(秒)
+    pub scroll_duration: f64,
+    /// 顶部/底部弹幕的显示时长(秒)
+    pub fixed_duration: f64,
+    /// 字号缩放，1.0时1080P下为48
+    pub font_scale: f64,
+    /// 不透明度，0-1
+    pub opacity: f64,
+    /// 相同内容在这段时间(秒)内只保留第一条，0为不合并
+    pub repeat_window: f64,
+}
+
+impl Default for DanmakuProfile {
+    fn default() -> Self {
+        DanmakuProfile {
+            block_keywords: Vec::new(),
+            block_regex: Vec::new(),
+            show_scroll: true,
+            show_top: true,
+            show_bottom: true,
+            show_advanced: true,
+            max_on_screen: 0,
+            scroll_duration: 8.0,
+            fixed_duration: 4.0,
+            font_scale: 1.0,
+            opacity: 0.8,
+            repeat_window: 0.0,
+        }
+    }
+}
+
+impl DanmakuProfile {
+    /// 从JSON文件读取规则，缺少的字段使用默认值
+    pub fn load(path: &str) -> Result<DanmakuProfile> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn shows(&self, mode: u8) -> bool {
+        match mode {
+            1..=3 | 6 => self.show_scroll,
+            4 => self.show_bottom,
+            5 => self.show_top,
+            _ => self.show_advanced,
+        }
+    }
+}
+
+/// 按规则过滤弹幕：类型、关键词、正则和重复刷屏
+pub fn filter(list: Vec<Danmaku>, profile: &DanmakuProfile) -> Result<Vec<Danmaku>> {
+    let regex = profile
+        .block_regex
+        .iter()
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| Regex::new(r).map_err(|e| anyhow!("Invalid danmaku regex {}: {}", r, e)))
+        .collect::<Result<Vec<Regex>>>()?;
+    let keywords: Vec<&str> = profile
+        .block_keywords
+        .iter()
+        .map(|k| k.trim())
+        .filter(|k| !k.is_empty())
+        .collect();
+    // 每种内容上次出现的时间，忽略空白和大小写
+    let mut last: HashMap<String, f64> = HashMap::new();
+    Ok(list
+        .into_iter()
+        .filter(|d| {
+            if !profile.shows(d.mode)
+                || keywords.iter().any(|k| d.text.contains(k))
+                || regex.iter().any(|r| r.is_match(&d.text))
+            {
+                return false;
+            }
+            if profile.repeat_window <= 0.0 {
+                return true;
+            }
+            let key: String = d
+                .text
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .flat_map(char::to_lowercase)
+                .collect();
+            match last.get(&key) {
+                Some(&time) if d.time - time < profile.repeat_window => false,
+                _ => {
+                    last.insert(key, d.time);
+                    true
+                }
+            }
+        })
+        .collect())
+}
+
+/// list.so返回的是deflate压缩的xml，未压缩时直接返回
+pub fn inflate(data: &[u8]) -> Result<String> {
+    if data.starts_with(b"<") {
+        return Ok(String::from_utf8_lossy(data).to_string());
+    }
+    let mut xml = String::new();
+    DeflateDecoder::new(data).read_to_string(&mut xml)?;
+    Ok(xml)
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// 解析xml弹幕，按时间排序
+///
+/// 弹幕内容中可能有xml不允许的控制字符，所以不使用严格的xml解析
+pub fn parse_xml(xml: &str) -> Vec<Danmaku> {
+    let re = Regex::new(r#"<d p="([^"]*)">([^<]*)</d>"#).unwrap();
+    let mut list: Vec<Danmaku> = re
+        .captures_iter(xml)
+        .filter_map(|c| {
+            let p: Vec<&str> = c[1].split(',').collect();
+            Some(Danmaku {
+                id: p.get(7).and_then(|id| id.parse().ok()).unwrap_or(0),
+                time: p.first()?.parse().ok()?,
+                mode: p.get(1)?.parse().ok()?,
+                size: p.get(2)?.parse().unwrap_or(25),
+                color: p.get(3)?.parse().unwrap_or(0xFFFFFF),
+                text: unescape(&c[2]),
+            })
+        })
+        .collect();
+    list.sort_by(|a, b| a.time.total_cmp(&b.time));
+    list
+}
+
+/// protobuf的一个字段值
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// 固定长度的字段，弹幕中不使用
+    Fixed,
+}
+
+/// 依次读取protobuf消息中的字段
+struct ProtoReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ProtoReader<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .data
+                .split_first()
+                .ok_or_else(|| anyhow!("Truncated varint"))?;
+            self.data = rest;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("Truncated field"));
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    /// 读取下一个字段，返回(字段号, 值)
+    fn next_field(&mut self) -> Result<Option<(u64, Field<'a>)>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Field::Fixed
+            }
+            wire => return Err(anyhow!("Unsupported wire type {}", wire)),
+        };
+        Ok(Some((key >> 3, field)))
+    }
+}
+
+/// 解析seg.so返回的DmSegMobileReply，elems为字段1
+pub fn parse_segment(data: &[u8]) -> Result<Vec<Danmaku>> {
+    let mut reader = ProtoReader { data };
+    let mut list = Vec::new();
+    while let Some((number, field)) = reader.next_field()? {
+        if let (1, Field::Bytes(elem)) = (number, field) {
+            list.push(parse_elem(elem)?);
+        }
+    }
+    Ok(list)
+}
+
+/// 解析DanmakuElem：1 id，2 progress(毫秒)，3 mode，4 fontsize，5 color，7 content
+fn parse_elem(data: &[u8]) -> Result<Danmaku> {
+    let mut reader = ProtoReader { data };
+    let mut danmaku = Danmaku {
+        id: 0,
+        time: 0.0,
+        mode: 1,
+        size: 25,
+        color: 0xFFFFFF,
+        text: String::new(),
+    };
+    while let Some((number, field)) = reader.next_field()? {
+        match (number, field) {
+            (1, Field::Varint(v)) => danmaku.id = v,
+            (2, Field::Varint(v)) => danmaku.time = v as f64 / 1000.0,
+            (3, Field::Varint(v)) => danmaku.mode = v as u8,
+            (4, Field::Varint(v)) => danmaku.size = v as u32,
+            (5, Field::Varint(v)) => danmaku.color = v as u32,
+            (7, Field::Bytes(v)) => danmaku.text = String::from_utf8_lossy(v).to_string(),
+            _ => {}
+        }
+    }
+    Ok(danmaku)
+}
+
+/// 合并多个分段的弹幕，按id去重(没有id时按时间和内容)，按时间排序
+pub fn merge(segments: Vec<Vec<Danmaku>>) -> Vec<Danmaku> {
+    let mut seen = HashSet::new();
+    let mut list: Vec<Danmaku> = segments
+        .into_iter()
+        .flatten()
+        .filter(|d| {
+            let key = if d.id != 0 {
+                d.id.to_string()
+            } else {
+                format!("{}\t{}", d.time, d.text)
+            };
+            seen.insert(key)
+        })
+        .collect();
+    list.sort_by(|a, b| a.time.total_cmp(&b.time));
+    list
+}
+
+/// 视频时长对应的分段数，时长未知时为0
+pub fn segment_count(duration: u64) -> u64 {
+    duration.div_ceil(SEGMENT_DURATION)
+}
+
+/// 估算文字宽度，ASCII按半个字宽计算
+fn text_width(text: &str, font_size: f64) -> f64 {
+    text.chars()
+        .map(|c| if c.is_ascii() { 0.5 } else { 1.0 })
+        .sum::<f64>()
+        * font_size
+}
+
+fn ass_time(seconds: f64) -> String {
+    let cs = (seconds.max(0.0) * 100.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        cs / 6000 % 60,
+        cs / 100 % 60,
+        cs % 100
+    )
+}
+
+/// ASS中大括号和反斜杠有特殊含义，替换为全角字符
+fn ass_text(text: &str) -> String {
+    text.replace('\\', "＼")
+        .replace('{', "｛")
+        .replace('}', "｝")
+        .replace("\r\n", "\\N")
+        .replace('\n', "\\N")
+        .replace("/n", "\\N")
+}
+
+/// 滚动轨道上最后一条弹幕：(出现时间, 宽度, 速度)
+type ScrollLane = Option<(f64, f64, f64)>;
+
+/// 转换为ASS字幕，width/height为视频分辨率
+pub fn to_ass(
+    list: &[Danmaku],
+    width: u32,
+    height: u32,
+    title: &str,
+    profile: &DanmakuProfile,
+) -> String {
+    let (w, h) = (width.max(1) as f64, height.max(1) as f64);
+    let font = (h / 1080.0 * 48.0 * profile.font_scale).round().max(12.0);
+    let scroll_duration = profile.scroll_duration.max(1.0);
+    let fixed_duration = profile.fixed_duration.max(1.0);
+    // ASS的alpha为透明度，00不透明
+    let alpha = ((1.0 - profile.opacity.clamp(0.0, 1.0)) * 255.0).round() as u8;
+    let line = (font * 1.2).round();
+    let lanes = ((h / line) as usize).max(1);
+
+    let mut out = format!(
+        "[Script Info]\nTitle: {}\nScriptType: v4.00+\nPlayResX: {}\nPlayResY: {}\n\
+         ScaledBorderAndShadow: yes\n\n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+         Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, \
+         Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Danmaku,Microsoft YaHei,{},&H{a:02X}FFFFFF,&H{a:02X}FFFFFF,&H{a:02X}000000,&H{a:02X}000000,\
+         -1,0,0,0,100,100,0,0,1,1,0,7,0,0,0,1\n\n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        title,
+        width,
+        height,
+        font,
+        a = alpha
+    );
+
+    let mut scroll: Vec<ScrollLane> = vec![None; lanes];
+    let mut top = vec![0.0f64; lanes];
+    let mut bottom = vec![0.0f64; lanes];
+    // 已输出弹幕的消失时间，用于限制同屏数量
+    let mut on_screen: Vec<f64> = Vec::new();
+    for d in list {
+        if profile.max_on_screen > 0 {
+            on_screen.retain(|&end| end > d.time);
+            if on_screen.len() >= profile.max_on_screen {
+                continue;
+            }
+        }
+        let size = (font * d.size as f64 / 25.0).round();
+        let text_w = text_width(&d.text, size);
+        let t = d.time;
+        let (position, duration) = match d.mode {
+            1..=3 | 6 => {
+                let speed = (w + text_w) / scroll_duration;
+                // 前一条弹幕已完全进入屏幕，且本条在其离开前不会追上
+                let free = |lane: &ScrollLane| match lane {
+                    None => true,
+                    Some((start, prev_w, prev_speed)) => {
+                        start + prev_w / prev_speed <= t && t + w / speed >= start + scroll_duration
+                    }
+                };
+                let lane = scroll.iter().position(free).unwrap_or_else(|| {
+                    // 没有空闲轨道时，选择最早空出的
+                    (0..lanes)
+                        .min_by(|&a, &b| {
+                            let end =
+                                |l: &ScrollLane| l.map(|(s, pw, ps)| s + pw / ps).unwrap_or(0.0);
+                            end(&scroll[a]).total_cmp(&end(&scroll[b]))
+                        })
+                        .unwrap_or(0)
+                });
+                scroll[lane] = Some((t, text_w, speed));
+                let y = lane as f64 * line;
+                (
+                    format!("\\move({},{},{},{})", w, y, -text_w, y),
+                    scroll_duration,
+                )
+            }
+            4 | 5 => {
+                let lanes = if d.mode == 5 { &mut top } else { &mut bottom };
+                let lane = lanes.iter().position(|&end| end <= t).unwrap_or_else(|| {
+                    (0..lanes.len())
+                        .min_by(|&a, &b| lanes[a].total_cmp(&lanes[b]))
+                        .unwrap_or(0)
+                });
+                lanes[lane] = t + fixed_duration;
+                let position = if d.mode == 5 {
+                    format!("\\an8\\pos({},{})", w / 2.0, lane as f64 * line)
+                } else {
+                    format!("\\an2\\pos({},{})", w / 2.0, h - lane as f64 * line)
+                };
+                (position, fixed_duration)
+            }
+            // 高级弹幕和代码弹幕无法转换
+            _ => continue,
+        };
+        if profile.max_on_screen > 0 {
+            on_screen.push(t + duration);
+        }
+        let mut style = position;
+        if d.size != 25 {
+            style.push_str(&format!("\\fs{}", size));
+        }
+        if d.color != 0xFFFFFF {
+            let (r, g, b) = (d.color >> 16 & 0xFF, d.color >> 8 & 0xFF, d.color & 0xFF);
+            style.push_str(&format!("\\c&H{:02X}{:02X}{:02X}&", b, g, r));
+            // 深色弹幕使用白色描边
+            if r * 299 + g * 587 + b * 114 < 60_000 {
+                style.push_str("\\3c&HFFFFFF&");
+            }
+        }
+        out.push_str(&format!(
+            "Dialogue: 2,{},{},Danmaku,,0,0,0,,{{{}}}{}\n",
+            ass_time(t),
+            ass_time(t + duration),
+            style,
+            ass_text(&d.text)
+        ));
+    }
+    out
+}
+
+#[test]
+fn test_danmaku() {
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?><i><chatid>1</chatid>
+<d p="5.5,5,25,16711680,1700000000,0,abc,1,10">顶部&amp;红色</d>
+<d p="1.0,1,25,16777215,1700000000,0,abc,2,10">第一条</d>
+<d p="1.2,1,25,16777215,1700000000,0,abc,3,10">第二条</d>
+<d p="2.0,7,25,16777215,1700000000,0,abc,4,10">[高级]</d>
+</i>"#;
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(xml.as_bytes()).unwrap();
+    let xml = inflate(&encoder.finish().unwrap()).unwrap();
+    let list = parse_xml(&xml);
+    assert_eq!(list.len(), 4);
+    assert_eq!(list[0].text, "第一条");
+    assert_eq!(list[3].text, "顶部&红色");
+
+    let ass = to_ass(&list, 1920, 1080, "t", &DanmakuProfile::default());
+    assert!(ass.contains("&H33FFFFFF"));
+    let events: Vec<&str> = ass.lines().filter(|l| l.starts_with("Dialogue")).collect();
+    assert_eq!(events.len(), 3);
+    // 第二条在第一条完全进入屏幕前出现，放到下一条轨道
+    assert!(events[0].contains("\\move(1920,0,-144,0)"));
+    assert!(events[1].contains(",58,"));
+    assert_eq!(
+        events[2],
+        "Dialogue: 2,0:00:05.50,0:00:09.50,Danmaku,,0,0,0,,{\\an8\\pos(960,0)\\c&H0000FF&}顶部&红色"
+    );
+}
+
+#[test]
+fn test_segment() {
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+    fn elem(id: u64, ms: u64, text: &str) -> Vec<u8> {
+        let mut e = Vec::new();
+        for (number, v) in [(1, id), (2, ms), (3, 1), (4, 25), (5, 0xFFFFFF)] {
+            varint(number << 3, &mut e);
+            varint(v, &mut e);
+        }
+        // midHash字段应被跳过
+        e.extend([6 << 3 | 2, 3]);
+        e.extend(b"abc");
+        e.push(7 << 3 | 2);
+        varint(text.len() as u64, &mut e);
+        e.extend(text.as_bytes());
+        let mut out = vec![1 << 3 | 2];
+        varint(e.len() as u64, &mut out);
+        out.extend(e);
+        out
+    }
+    let first = [elem(300, 361_500, "第二段"), elem(1, 1_000, "第一条")].concat();
+    let second = elem(300, 361_500, "第二段");
+    let list = merge(vec![
+        parse_segment(&first).unwrap(),
+        parse_segment(&second).unwrap(),
+    ]);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].text, "第一条");
+    assert_eq!(list[1].id, 300);
+    assert_eq!(list[1].time, 361.5);
+    assert!(parse_segment(&first[..first.len() - 1]).is_err());
+    assert_eq!(segment_count(361), 2);
+    assert_eq!(segment_count(0), 0);
+}
+
+#[test]
+fn test_profile() {
+    let danmaku = |time: f64, mode: u8, text: &str| Danmaku {
+        id: 0,
+        time,
+        mode,
+        size: 25,
+        color: 0xFFFFFF,
+        text: text.to_string(),
+    };
+    let list = vec![
+        danmaku(1.0, 1, "前方高能"),
+        danmaku(2.0, 1, "前方 高能"),
+        danmaku(3.0, 1, "剧透警告"),
+        danmaku(4.0, 5, "顶部"),
+        danmaku(5.0, 1, "666666"),
+        danmaku(20.0, 1, "前方高能"),
+    ];
+    let profile: DanmakuProfile = serde_json::from_str(
+        r#"{"block_keywords": ["剧透"], "block_regex": ["^6+$"], "show_top": false, "repeat_window": 10}"#,
+    )
+    .unwrap();
+    assert_eq!(profile.scroll_duration, 8.0);
+    let filtered = filter(list.clone(), &profile).unwrap();
+    let times: Vec<f64> = filtered.iter().map(|d| d.time).collect();
+    assert_eq!(times, [1.0, 20.0]);
+    let invalid = DanmakuProfile {
+        block_regex: vec!["(".to_string()],
+        ..Default::default()
+    };
+    assert!(filter(list.clone(), &invalid).is_err());
+    // 逗号后带空格输入的关键词
+    let spaced = DanmakuProfile {
+        block_keywords: "高能, 剧透".split(',').map(String::from).collect(),
+        ..Default::default()
+    };
+    let times: Vec<f64> = filter(list.clone(), &spaced)
+        .unwrap()
+        .iter()
+        .map(|d| d.time)
+        .collect();
+    assert_eq!(times, [4.0, 5.0]);
+
+    let profile = DanmakuProfile {
+        max_on_screen: 2,
+        opacity: 1.0,
+        ..Default::default()
+    };
+    let ass = to_ass(&list, 1920, 1080, "t", &profile);
+    assert!(ass.contains("&H00FFFFFF"));
+    // 前两条滚动8秒，第3、4、5条出现时同屏已满
+    assert_eq!(ass.lines().filter(|l| l.starts_with("Dialogue")).count(), 3);
+}
@@ -1,22 +1,35 @@
 use anyhow::{Context, Ok, Result};
 use chrono::Utc;
 use futures_util::TryStreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use reqwest::header::HeaderMap;
 use reqwest::Client;
 use serde_json::{self, Value};
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::Path;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::Command;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
 
-use crate::down_bv::get_pic;
-use crate::refresh_cookie::{create_headers, Cookies};
+use crate::burn::{self, BurnSource};
+use crate::chapter;
+use crate::config::{Config, Container};
+use crate::danmaku;
+use crate::down_bv::{fill_player_metadata, get_player_info, get_subtitle_langs, save_cover};
+use crate::ffmpeg;
+use crate::inspect::{parse_dash, video_size, MediaInfo};
+use crate::metadata::{format_date, Metadata};
+use crate::profile;
+use crate::progress;
+use crate::refresh_cookie::{create_client, create_headers, read_cookie_or_not};
+use crate::remux::{self, Segment, SegmentReader, StreamMuxer};
 use crate::resolution;
+use crate::subtitle::{self, SubtitleTrack};
+use crate::verify;
 
-pub async fn down_main((ep_id, season_id): (&str, &str), rsl: &str) -> Result<()> {
-    download_bangumi(ep_id, season_id, rsl).await?;
+pub async fn down_main((ep_id, season_id): (&str, &str), rsl: &str, config: &Config) -> Result<()> {
+    download_bangumi(ep_id, season_id, rsl, config).await?;
     Ok(())
 }
 
@@ -107,12 +120,29 @@ fn get_file_url(response: &Value, rsl: &str) -> Result<(String, String, i32)> {
     Ok((url_video.to_string(), url_audio.to_string(), qn))
 }
 
+/// 获取dash中最高音质的音频地址，有无损音轨时优先，返回(地址, 是否为flac, 音质id)
+pub fn get_audio_url(dash: &Value) -> Result<(String, bool, i64)> {
+    let flac = &dash["flac"]["audio"];
+    if let Some(url) = flac["baseUrl"].as_str() {
+        return Ok((url.to_string(), true, flac["id"].as_i64().unwrap_or(0)));
+    }
+    let audio = dash["audio"]
+        .as_array()
+        .context("Missing or invalid audio array in response JSON")?
+        .iter()
+        .max_by_key(|a| a["bandwidth"].as_i64().unwrap_or(0))
+        .context("No valid audio streams found")?;
+    let url = audio["baseUrl"].as_str().unwrap_or("").to_string();
+    Ok((url, false, audio["id"].as_i64().unwrap_or(0)))
+}
+
 async fn down_from_url(url: &str, client: Client, headers: HeaderMap, path: &str) -> Result<()> {
     let resp = client
         .get(url)
         .headers(headers.clone())
         .send()
         .await
+        .and_then(|resp| resp.error_for_status())
         .context("Failed to download video stream")?;
     let total_size = resp.content_length().unwrap_or(0);
     let pb = ProgressBar::new(total_size);
@@ -124,19 +154,43 @@ async fn down_from_url(url: &str, client: Client, headers: HeaderMap, path: &str
 
     pb.enable_steady_tick(std::time::Duration::from_millis(100));
 
+    progress::start(&format!("下载 {}", file_name(path)), total_size);
     let mut file = File::create(&path).await?;
     let mut stream = resp.bytes_stream();
+    let mut received = 0;
     while let Some(chunk) = stream.try_next().await? {
         let chunk = chunk;
         file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
 
         pb.inc(chunk.len() as u64);
+        progress::inc(chunk.len() as u64);
     }
-    pb.set_position(total_size);
+    file.flush().await?;
+    check_size(path, received, total_size)?;
+    progress::finish();
     pb.finish_with_message("Downloaded stream");
     Ok(())
 }
 
+/// 检查收到的字节数与content_length是否一致，total为0(未知)时跳过
+fn check_size(name: &str, received: u64, total: u64) -> Result<()> {
+    if total > 0 && received != total {
+        return Err(anyhow::anyhow!(
+            "Incomplete download of {}: {} of {} bytes",
+            file_name(name),
+            received,
+            total
+        ));
+    }
+    Ok(())
+}
+
+/// 去掉 ./download/ 前缀，用于显示
+pub fn file_name(path: &str) -> &str {
+    path.trim_start_matches("./download/")
+}
+
 /// 下载番剧文件
 async fn down_file_bangumi(
     url_response: Value,
@@ -145,8 +199,31 @@ async fn down_file_bangumi(
     client: &Client,
     headers: HeaderMap,
     rsl: &str,
+    config: &Config,
 ) -> Result<()> {
+    let mut metadata = get_bangumi_metadata(&name_response, ep_id);
+    metadata.duration = url_response["result"]["dash"]["duration"]
+        .as_u64()
+        .unwrap_or(0);
+    let (bvid, cid) = get_episode_ids(&name_response, ep_id);
+    fill_player_metadata(client, &bvid, &cid, headers.clone(), &mut metadata, config).await;
+    metadata.cid = cid;
+    if config.audio_only {
+        let name = remove_punctuation(&get_bangumi_name_from_json(name_response, ep_id));
+        let id = format!("ep{}", ep_id);
+        return down_audio(
+            client,
+            &url_response["result"]["dash"],
+            &name,
+            &metadata,
+            headers,
+            &id,
+            config,
+        )
+        .await;
+    }
     let (url_video, url_audio, qn) = get_file_url(&url_response, rsl)?;
+    (metadata.width, metadata.height) = video_size(&url_response["result"]["dash"], &url_video);
     let qn_c = resolution::qn(rsl);
     if qn != qn_c.parse::<i32>().unwrap() {
         println!("此分辨率不存在，将下载默认分辨率");
@@ -159,9 +236,180 @@ async fn down_file_bangumi(
 
     let bangumi_name = format!("{} {}", bangumi_name, rsl);
 
+    if !Path::new("./download").exists() {
+        std::fs::create_dir_all("./download")?;
+    }
+    let urls = [url_video.as_str(), url_audio.as_str()];
+    download_video_audio(client, headers, urls, &bangumi_name, &metadata, config).await
+}
+
+/// 下载视频和音频并合并，校验失败时删除不完整的文件并重试
+pub async fn download_video_audio(
+    client: &Client,
+    headers: HeaderMap,
+    urls: [&str; 2],
+    name: &str,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<()> {
+    let subtitles = down_subtitles(client, headers.clone(), name, metadata, config).await;
+    down_danmaku(client, headers.clone(), name, metadata, config).await;
+    let output_path = config.container.output_path(name);
+    if Path::new(&output_path).exists() {
+        println!("{} already exists", output_path);
+        write_log(&metadata.id, file_name(&output_path), "exists").await?;
+        return Ok(());
+    }
+    if config.needs_ffmpeg() {
+        let version = ffmpeg::check_ffmpeg(&config.ffmpeg_path).await?;
+        println!("ffmpeg {}", version);
+    }
+    println!("downloading {}", name);
+    let output = final_output(name, config);
+    let (headers, subtitles) = (&headers, &subtitles);
+    let log_name = file_name(&output_path);
+    retry(
+        &metadata.id,
+        log_name,
+        output.as_deref(),
+        config,
+        move || {
+            download_once(
+                client,
+                headers.clone(),
+                urls,
+                name,
+                metadata,
+                subtitles,
+                config,
+            )
+        },
+    )
+    .await
+}
+
+async fn download_once(
+    client: &Client,
+    headers: HeaderMap,
+    urls: [&str; 2],
+    name: &str,
+    metadata: &Metadata,
+    subtitles: &[(SubtitleTrack, String)],
+    config: &Config,
+) -> Result<()> {
+    if config.streams() {
+        return stream_video_audio(client, headers, urls, name, metadata, config).await;
+    }
+    let paths = [
+        format!("./download/{}_video.m4s", name),
+        format!("./download/{}_audio.m4s", name),
+    ];
+    for (url, path) in urls.iter().zip(&paths) {
+        down_stream(url, client, headers.clone(), path).await?;
+    }
+    let cover = down_cover(client, metadata, name, headers, config).await;
+    let burn_source = burn::burn_source(name, subtitles, config);
+    let subtitles = if config.embed_subtitles {
+        subtitles
+    } else {
+        &[]
+    };
+    concat_video_audio(
+        name.to_string(),
+        metadata,
+        cover.as_deref(),
+        subtitles,
+        config,
+    )
+    .await?;
+    match (config.burn, &burn_source) {
+        (BurnSource::None, _) => {}
+        (_, Some(source)) => {
+            let has_cover = cover
+                .as_deref()
+                .is_some_and(|c| config.embed_cover && is_embeddable(c));
+            burn::burn_in(name, source, has_cover, metadata.duration, config).await?;
+        }
+        (burn, None) => eprintln!("No {} to burn into {}", burn.name(), name),
+    }
+    remove_cover(cover.as_deref(), config)?;
+    if config.container != Container::Raw {
+        let output_path = config.container.output_path(name);
+        verify::check_duration(&output_path, metadata.duration, config).await?;
+        // 校验通过后才删除m4s，合并或校验失败时重试只需重新合并
+        for path in &paths {
+            std::fs::remove_file(path)?;
+        }
+    }
+    println!("Concat completed for {}", name);
+    Ok(())
+}
+
+/// 执行下载，失败时按设置重试，每次的结果写入下载记录
+///
+/// 失败时只删除不完整的合并结果；m4s由下载步骤自行处理，已校验的流保留，
+/// 重试时只需重新合并，最后一次失败后也可以手动合并。output为None时(raw)不删除任何文件
+async fn retry<F, Fut>(
+    id: &str,
+    log_name: &str,
+    output: Option<&str>,
+    config: &Config,
+    mut attempt: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let attempts = config.retries + 1;
+    let mut n = 1;
+    loop {
+        let Err(error) = attempt().await else {
+            return write_log(id, log_name, "ok").await;
+        };
+        eprintln!("Error occurred: {:#}", error);
+        let status = format!("failed {}/{}: {}", n, attempts, error);
+        write_log(id, log_name, &status).await?;
+        if let Some(output) = output.filter(|o| Path::new(o).exists()) {
+            std::fs::remove_file(output)?;
+        }
+        if n >= attempts {
+            return Err(error);
+        }
+        n += 1;
+        println!("retrying {}/{}", n, attempts);
+    }
+}
+
+/// 合并后的输出文件，raw时输出就是m4s，没有单独的合并结果
+fn final_output(name: &str, config: &Config) -> Option<String> {
+    (config.container != Container::Raw).then(|| config.container.output_path(name))
+}
+
+/// 下载一条流并校验，已有校验通过的文件时跳过
+///
+/// 下载或校验失败时只删除这条流，其他已完成的流留给重试使用
+async fn down_stream(url: &str, client: &Client, headers: HeaderMap, path: &str) -> Result<()> {
+    if Path::new(path).exists() && verify::check_stream(path).is_ok() {
+        println!("{} already downloaded", file_name(path));
+        return Ok(());
+    }
+    let result = async {
+        down_from_url(url, client.clone(), headers, path).await?;
+        verify::check_stream(path)
+    }
+    .await;
+    if result.is_err() && Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    result
+}
+
+/// 写入下载记录，status为ok/exists/failed及原因
+pub async fn write_log(id: &str, name: &str, status: &str) -> Result<()> {
     let time = Utc::now() + chrono::Duration::hours(8);
     let time_ = time.format("%Y-%m-%d %H:%M:%S");
-    let data = format!("{}\tep{}\t{}\t\n", time_, ep_id, bangumi_name);
+    let status = status.replace(['\t', '\n'], " ");
+    let data = format!("{}\t{}\t{}\t{}\n", time_, id, name, status);
     let path = Path::new("dat.log");
     if !path.exists() {
         let mut file = tokio::fs::File::create(path).await?;
@@ -173,83 +421,577 @@ async fn down_file_bangumi(
             .await?;
         file.write_all(data.as_bytes()).await?;
     }
+    Ok(())
+}
+
+/// 只下载音频并写入标签和封面
+pub async fn down_audio(
+    client: &Client,
+    dash: &Value,
+    name: &str,
+    metadata: &Metadata,
+    headers: HeaderMap,
+    id: &str,
+    config: &Config,
+) -> Result<()> {
+    let (url_audio, flac, audio_id) = get_audio_url(dash)?;
+    let ext = if flac { "flac" } else { "m4a" };
+    let audio_id = audio_id.to_string();
+    let name = format!("{} {}", name, resolution::audio(&audio_id))
+        .trim()
+        .to_string();
 
     if !Path::new("./download").exists() {
         std::fs::create_dir_all("./download")?;
     }
-    let video_path = format!("./download/{}_video.m4s", bangumi_name);
-    let audio_path = format!("./download/{}_audio.m4s", bangumi_name);
-    let output_path = format!("./download/{}.mp4", bangumi_name);
-
+    let output_path = format!("./download/{}.{}", name, ext);
     if Path::new(&output_path).exists() {
-        println!("{} already exists", bangumi_name);
+        println!("{} already exists", output_path);
+        write_log(id, file_name(&output_path), "exists").await?;
         return Ok(());
     }
-    println!("downloading {}", bangumi_name);
+    let version = ffmpeg::check_ffmpeg(&config.ffmpeg_path).await?;
+    println!("ffmpeg {}", version);
+    println!("downloading {}", name);
 
-    let urls = vec![(url_video, video_path), (url_audio, audio_path)];
-    for (url, path) in urls {
-        let client = client.clone();
-        let headers = headers.clone();
-        down_from_url(&url, client, headers, &path).await?;
+    let (headers, url_audio, name, output) = (&headers, &url_audio, &name, &output_path);
+    retry(id, file_name(output), Some(output), config, move || {
+        extract_audio(
+            client,
+            headers.clone(),
+            url_audio,
+            name,
+            output,
+            metadata,
+            config,
+        )
+    })
+    .await
+}
+
+async fn extract_audio(
+    client: &Client,
+    headers: HeaderMap,
+    url_audio: &str,
+    name: &str,
+    output_path: &str,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<()> {
+    let audio_path = format!("./download/{}_audio.m4s", name);
+    down_stream(url_audio, client, headers.clone(), &audio_path).await?;
+    let cover = down_cover(client, metadata, name, headers, config).await;
+
+    export_chapters(name, metadata, config)?;
+    let ffmetadata = write_ffmetadata(name, metadata)?;
+
+    let mut args: Vec<String> = vec!["-i".to_string(), audio_path.clone()];
+    let embed = cover
+        .as_deref()
+        .filter(|c| config.embed_cover && is_embeddable(c));
+    match embed {
+        Some(cover) => {
+            args.extend(["-i", cover, "-map", "0:a", "-map", "1:v"].map(String::from));
+            args.extend(["-c:v", "copy", "-disposition:v:0", "attached_pic"].map(String::from));
+        }
+        None => args.extend(["-map", "0:a"].map(String::from)),
+    }
+    if let Some(path) = &ffmetadata {
+        let index = if embed.is_some() { "2" } else { "1" };
+        args.extend(["-i", path, "-map_chapters", index].map(String::from));
     }
+    args.extend(["-c:a", "copy"].map(String::from));
+    args.extend(metadata.ffmpeg_args());
+    args.extend(["-y", output_path, "-hide_banner", "-loglevel", "error"].map(String::from));
 
-    concat_video_audio(bangumi_name.clone()).await?;
-    println!("Concat completed for {}", bangumi_name);
+    let stage = format!("提取音频 {}", name);
+    let result = ffmpeg::run_ffmpeg(&config.ffmpeg_path, &args, &stage, metadata.duration).await;
+    remove_ffmetadata(ffmetadata.as_deref())?;
+    result.with_context(|| format!("Failed to extract audio for {}", name))?;
+    remove_cover(cover.as_deref(), config)?;
+    verify::check_duration(output_path, metadata.duration, config).await?;
+    println!("{}", output_path);
+    // 校验通过后才删除m4s，提取或校验失败时重试只需重新提取
+    std::fs::remove_file(&audio_path)?;
     Ok(())
 }
 
-/// 合并视频和音频文件
-pub async fn concat_video_audio(name: String) -> Result<()> {
+/// 按设置下载封面，失败时只打印错误
+pub async fn down_cover(
+    client: &Client,
+    metadata: &Metadata,
+    name: &str,
+    headers: HeaderMap,
+    config: &Config,
+) -> Option<String> {
+    if !config.save_cover && !config.embed_cover {
+        return None;
+    }
+    save_cover(
+        client,
+        &metadata.cover,
+        name,
+        config.cover_original,
+        headers,
+    )
+    .await
+    .map_err(|e| eprintln!("Error occurred: {}", e))
+    .ok()
+}
+
+/// 按设置下载字幕并转换格式，保存为 {name}.{语言}.{格式}，返回保存的文件；失败时只打印错误
+pub async fn down_subtitles(
+    client: &Client,
+    headers: HeaderMap,
+    name: &str,
+    metadata: &Metadata,
+    config: &Config,
+) -> Vec<(SubtitleTrack, String)> {
+    let mut saved = Vec::new();
+    if !config.subtitles {
+        return saved;
+    }
+    let format = config.subtitle_format;
+    for track in subtitle::select(&metadata.subtitles, &config.subtitle_langs) {
+        let path = format!("./download/{}.{}.{}", name, track.lan, format.name());
+        if !Path::new(&path).exists() {
+            let result = async {
+                let json: Value = client
+                    .get(&track.url)
+                    .headers(headers.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let cues = subtitle::parse_cues(&json);
+                let title = format!("{} {}", metadata.title, track.lan_doc);
+                std::fs::write(&path, subtitle::convert(&cues, format, &title))?;
+                Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                eprintln!("Error occurred: {}", e);
+                continue;
+            }
+            println!("{}", path);
+        }
+        saved.push((track.clone(), path));
+    }
+    saved
+}
+
+/// 按设置下载弹幕并转换为ASS，保存为 {name}.danmaku.ass，可另存原始JSON；失败时只打印错误
+pub async fn down_danmaku(
+    client: &Client,
+    headers: HeaderMap,
+    name: &str,
+    metadata: &Metadata,
+    config: &Config,
+) -> Option<String> {
+    if !config.danmaku || metadata.cid.is_empty() {
+        return None;
+    }
+    let path = format!("./download/{}.danmaku.ass", name);
+    if Path::new(&path).exists() {
+        return Some(path);
+    }
+    let result = async {
+        // 分段接口没有数量上限，失败时退回xml接口
+        let list = match fetch_danmaku_segments(client, headers.clone(), metadata).await {
+            Err(e) => {
+                eprintln!(
+                    "Failed to fetch danmaku segments, falling back to xml: {}",
+                    e
+                );
+                fetch_danmaku_xml(client, headers, &metadata.cid).await?
+            }
+            list => list?,
+        };
+        // JSON保存合并去重后的全部弹幕，过滤规则只用于ASS
+        if config.danmaku_json {
+            let json_path = format!("./download/{}.danmaku.json", name);
+            std::fs::write(&json_path, serde_json::to_string_pretty(&list)?)?;
+            println!("{}", json_path);
+        }
+        let list = danmaku::filter(list, &config.danmaku_profile)?;
+        // 分辨率未知时按1080P排版
+        let (width, height) = match (metadata.width, metadata.height) {
+            (0, _) | (_, 0) => (1920, 1080),
+            size => size,
+        };
+        let ass = danmaku::to_ass(
+            &list,
+            width,
+            height,
+            &metadata.title,
+            &config.danmaku_profile,
+        );
+        std::fs::write(&path, ass)?;
+        Ok(())
+    }
+    .await;
+    match result {
+        Err(e) => {
+            eprintln!("Error occurred: {}", e);
+            None
+        }
+        _ => {
+            println!("{}", path);
+            Some(path)
+        }
+    }
+}
+
+/// 按6分钟一段下载protobuf弹幕，合并去重
+///
+/// 时长未知时依次下载，直到遇到空的分段
+async fn fetch_danmaku_segments(
+    client: &Client,
+    headers: HeaderMap,
+    metadata: &Metadata,
+) -> Result<Vec<danmaku::Danmaku>> {
+    let count = danmaku::segment_count(metadata.duration);
+    let mut segments = Vec::new();
+    let mut index = 1;
+    while count == 0 || index <= count {
+        let data = client
+            .get("https://api.bilibili.com/x/v2/dm/web/seg.so")
+            .headers(headers.clone())
+            .query(&[
+                ("type", "1"),
+                ("oid", metadata.cid.as_str()),
+                ("segment_index", &index.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let segment = danmaku::parse_segment(&data)?;
+        if count == 0 && segment.is_empty() {
+            break;
+        }
+        segments.push(segment);
+        index += 1;
+    }
+    Ok(danmaku::merge(segments))
+}
+
+async fn fetch_danmaku_xml(
+    client: &Client,
+    headers: HeaderMap,
+    cid: &str,
+) -> Result<Vec<danmaku::Danmaku>> {
+    let data = client
+        .get("https://api.bilibili.com/x/v1/dm/list.so")
+        .headers(headers)
+        .query(&[("oid", cid)])
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(danmaku::parse_xml(&danmaku::inflate(&data)?))
+}
+
+/// 不保留封面文件时，嵌入后删除
+pub fn remove_cover(cover: Option<&str>, config: &Config) -> Result<()> {
+    if let Some(cover) = cover {
+        if !config.save_cover {
+            std::fs::remove_file(cover)?;
+        }
+    }
+    Ok(())
+}
+
+/// 按设置另存OGM格式的章节文本
+fn export_chapters(name: &str, metadata: &Metadata, config: &Config) -> Result<()> {
+    if config.export_chapters && !metadata.chapters.is_empty() {
+        let path = format!("./download/{}.chapters.txt", name);
+        std::fs::write(path, chapter::to_ogm(&metadata.chapters))?;
+    }
+    Ok(())
+}
+
+/// 生成ffmpeg读取章节用的元数据文件，没有章节时为None
+fn write_ffmetadata(name: &str, metadata: &Metadata) -> Result<Option<String>> {
+    if metadata.chapters.is_empty() {
+        return Ok(None);
+    }
+    let path = format!("./download/{}.ffmetadata", name);
+    std::fs::write(&path, chapter::to_ffmetadata(&metadata.chapters))?;
+    Ok(Some(path))
+}
+
+fn remove_ffmetadata(path: Option<&str>) -> Result<()> {
+    if let Some(path) = path {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// mp4/mkv/m4a中只能嵌入jpg和png封面
+fn is_embeddable(path: &str) -> bool {
+    [".jpg", ".jpeg", ".png"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// 合并视频和音频文件，raw时保留原始m4s不合并；subtitles为要嵌入的(字幕, 文件)
+pub async fn concat_video_audio(
+    name: String,
+    metadata: &Metadata,
+    cover: Option<&str>,
+    subtitles: &[(SubtitleTrack, String)],
+    config: &Config,
+) -> Result<()> {
     if !Path::new("./download").exists() {
         std::fs::create_dir_all("./download")?;
     }
-    let name_mp4 = format!("./download/{}.mp4", name);
+    export_chapters(&name, metadata, config)?;
+    let container = config.container;
+    if container == Container::Raw {
+        return Ok(());
+    }
+    let name_out = container.output_path(&name);
     let name_video = format!("./download/{}_video.m4s", name);
     let name_audio = format!("./download/{}_audio.m4s", name);
-    let handle = tokio::spawn(async move {
-        let name_mp4 = name_mp4;
-        if Path::new(&name_mp4).exists() {
-            return;
+    let cover = cover.filter(|c| config.embed_cover && is_embeddable(c));
+    // 内置合并不支持字幕轨，有字幕时使用ffmpeg
+    if container == Container::Mp4 && !config.use_ffmpeg && subtitles.is_empty() {
+        if Path::new(&name_out).exists() {
+            return Ok(());
         }
-        let status = Command::new("ffmpeg")
-            .args(&[
-                "-i",
-                name_video.as_str(),
-                "-i",
-                name_audio.as_str(),
-                "-c:v",
-                "copy",
-                "-c:a",
-                "copy",
-                "-shortest",
-                "-map",
-                "0:v",
-                "-map",
-                "1:a",
-                "-y",
-                "-movflags",
-                "+faststart",
-                name_mp4.as_str(),
-                "-hide_banner",
-                "-stats",
-                "-loglevel",
-                "error",
-            ])
-            .stdin(std::process::Stdio::null())
-            .status()
-            .await
-            .expect("Failed to execute ffmpeg");
-
-        if status.success() {
-            println!("{}", name_mp4);
-            std::fs::remove_file(name_video).unwrap();
-            std::fs::remove_file(name_audio).unwrap();
+        let (out, video, audio) = (name_out.clone(), name_video.clone(), name_audio.clone());
+        let metadata = metadata.clone();
+        let cover = cover.map(|c| c.to_string());
+        tokio::task::spawn_blocking(move || {
+            remux::remux(
+                &[Path::new(&video), Path::new(&audio)],
+                Path::new(&out),
+                &metadata,
+                cover.as_deref().map(Path::new),
+            )
+        })
+        .await??;
+        println!("{}", name_out);
+        return Ok(());
+    }
+    if Path::new(&name_out).exists() {
+        return Ok(());
+    }
+    let mut args: Vec<String> = ["-i", name_video.as_str(), "-i", name_audio.as_str()]
+        .map(String::from)
+        .to_vec();
+    if let (Some(cover), Container::Mp4) = (cover, container) {
+        args.extend(["-i", cover].map(String::from));
+    }
+    let first_subtitle = args.len() / 2;
+    for (_, path) in subtitles {
+        args.extend(["-i", path].map(String::from));
+    }
+    let ffmetadata = write_ffmetadata(&name, metadata)?;
+    if let Some(path) = &ffmetadata {
+        let index = args.len() / 2;
+        args.extend(["-i".to_string(), path.clone()]);
+        args.extend(["-map_chapters".to_string(), index.to_string()]);
+    }
+    args.extend(
+        [
+            "-c:v", "copy", "-c:a", "copy", "-map", "0:v", "-map", "1:a", "-y",
+        ]
+        .map(String::from),
+    );
+    // 字幕流会提前结束，有字幕时不能使用-shortest
+    if subtitles.is_empty() {
+        args.push("-shortest".to_string());
+    }
+    if let Some(cover) = cover {
+        // mp4中作为封面视频流，mkv中作为附件
+        if container == Container::Mp4 {
+            args.extend(["-map", "2:v", "-disposition:v:1", "attached_pic"].map(String::from));
         } else {
-            eprintln!("Fail!");
+            let mimetype = if cover.ends_with(".png") {
+                "mimetype=image/png"
+            } else {
+                "mimetype=image/jpeg"
+            };
+            args.extend(["-attach", cover, "-metadata:s:t", mimetype].map(String::from));
         }
-    });
-    handle.await?;
+    }
+    if container == Container::Mp4 {
+        args.extend(["-movflags", "+faststart"].map(String::from));
+    }
+    if !subtitles.is_empty() {
+        args.extend(subtitle_args(subtitles, first_subtitle, container, config));
+    }
+    args.extend(metadata.ffmpeg_args());
+    args.extend([name_out.as_str(), "-hide_banner", "-loglevel", "error"].map(String::from));
+    let stage = format!("合并 {}", name);
+    let result = ffmpeg::run_ffmpeg(&config.ffmpeg_path, &args, &stage, metadata.duration).await;
+    remove_ffmetadata(ffmetadata.as_deref())?;
+    result.with_context(|| format!("Failed to concat {}", name))?;
+    println!("{}", name_out);
+    Ok(())
+}
+
+/// 嵌入字幕轨的ffmpeg参数，first为第一个字幕输入的序号
+fn subtitle_args(
+    subtitles: &[(SubtitleTrack, String)],
+    first: usize,
+    container: Container,
+    config: &Config,
+) -> Vec<String> {
+    // mp4只支持mov_text，mkv可以直接保存srt/ass/vtt
+    let codec = if container == Container::Mp4 {
+        "mov_text"
+    } else {
+        "copy"
+    };
+    let mut args: Vec<String> = ["-c:s", codec].map(String::from).to_vec();
+    let default = subtitles
+        .iter()
+        .position(|(t, _)| t.lan.eq_ignore_ascii_case(config.default_subtitle.trim()));
+    for (i, (track, _)) in subtitles.iter().enumerate() {
+        let disposition = if Some(i) == default { "default" } else { "0" };
+        args.extend([
+            "-map".to_string(),
+            format!("{}:s", first + i),
+            format!("-metadata:s:s:{}", i),
+            format!("language={}", subtitle::iso639_2(&track.lan)),
+            format!("-metadata:s:s:{}", i),
+            format!("title={}", track.lan_doc),
+            format!("-disposition:s:{}", i),
+            disposition.to_string(),
+        ]);
+    }
+    args
+}
+
+/// 边下载边合并为mp4，不生成m4s中间文件
+pub async fn stream_video_audio(
+    client: &Client,
+    headers: HeaderMap,
+    urls: [&str; 2],
+    name: &str,
+    metadata: &Metadata,
+    config: &Config,
+) -> Result<()> {
+    let output = config.container.output_path(name);
+    let cover = down_cover(client, metadata, name, headers.clone(), config).await;
+    export_chapters(name, metadata, config)?;
+    let embed = cover
+        .as_deref()
+        .filter(|c| config.embed_cover && is_embeddable(c));
+    let result = stream_mux(client, headers, &urls, &output, metadata, embed).await;
+    remove_cover(cover.as_deref(), config)?;
+    result.with_context(|| format!("Failed to stream {}", name))?;
+    verify::check_duration(&output, metadata.duration, config).await?;
+    println!("{}", output);
+    Ok(())
+}
+
+async fn stream_mux(
+    client: &Client,
+    headers: HeaderMap,
+    urls: &[&str],
+    output: &str,
+    metadata: &Metadata,
+    cover: Option<&str>,
+) -> Result<()> {
+    let cover = match cover {
+        Some(path) => std::fs::read(path)?,
+        None => Vec::new(),
+    };
+    let n = urls.len();
+    let mut muxer = StreamMuxer::create(Path::new(output), n)?;
+    let multi = MultiProgress::new();
+    progress::start(&format!("下载并合并 {}", file_name(output)), 0);
+    let mut receivers = Vec::new();
+    let mut tasks = Vec::new();
+    for url in urls {
+        // 只缓冲少量片段，较快的流会等待较慢的流，保证按时间交错写入
+        let (tx, rx) = mpsc::channel(4);
+        receivers.push(rx);
+        tasks.push(tokio::spawn(stream_segments(
+            url.to_string(),
+            client.clone(),
+            headers.clone(),
+            multi.clone(),
+            tx,
+        )));
+    }
+
+    let mut pending: Vec<Option<Segment>> = (0..n).map(|_| None).collect();
+    let mut done = vec![false; n];
+    loop {
+        for i in 0..n {
+            while pending[i].is_none() && !done[i] {
+                match receivers[i].recv().await {
+                    Some(Segment::Init(moov)) => muxer.add(i, Segment::Init(moov))?,
+                    Some(segment) => pending[i] = Some(segment),
+                    None => done[i] = true,
+                }
+            }
+        }
+        let next = (0..n)
+            .filter(|&i| pending[i].is_some())
+            .min_by(|&a, &b| muxer.time(a).total_cmp(&muxer.time(b)));
+        let Some(i) = next else { break };
+        if let Some(segment) = pending[i].take() {
+            muxer.add(i, segment)?;
+        }
+    }
+    for task in tasks {
+        task.await??;
+    }
+    muxer.finish(metadata, &cover)?;
+    progress::finish();
+    Ok(())
+}
+
+/// 下载一个流，按片段发送给合并端
+async fn stream_segments(
+    url: String,
+    client: Client,
+    headers: HeaderMap,
+    multi: MultiProgress,
+    tx: mpsc::Sender<Segment>,
+) -> Result<()> {
+    let resp = client
+        .get(&url)
+        .headers(headers)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .context("Failed to download stream")?;
+    let total_size = resp.content_length().unwrap_or(0);
+    let pb = multi.add(ProgressBar::new(total_size));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) ")?
+            .progress_chars("=> "),
+    );
+    progress::add_total(total_size);
+
+    let mut reader = SegmentReader::default();
+    let mut stream = resp.bytes_stream();
+    let mut received = 0;
+    while let Some(chunk) = stream.try_next().await? {
+        received += chunk.len() as u64;
+        pb.inc(chunk.len() as u64);
+        progress::inc(chunk.len() as u64);
+        for segment in reader.push(&chunk)? {
+            tx.send(segment)
+                .await
+                .map_err(|_| anyhow::anyhow!("Muxer stopped"))?;
+        }
+    }
+    let file = url.split('?').next().unwrap_or(&url).rsplit('/').next();
+    check_size(file.unwrap_or(&url), received, total_size)?;
+    reader.finish()?;
+    pb.finish_with_message("Downloaded stream");
     Ok(())
 }
 
@@ -292,6 +1034,81 @@ fn get_bangumi_name_from_json(json: Value, ep_id: &str) -> String {
     bangumi_name.to_string()
 }
 
+/// 从json文件中获取该ep_id对应的标签信息
+fn get_bangumi_metadata(json: &Value, ep_id: &str) -> Metadata {
+    let ep_id = ep_id.parse::<i64>().unwrap_or(0);
+    let result = &json["result"];
+    let episodes = result["episodes"].as_array();
+    let ep_index = episodes
+        .and_then(|episodes| {
+            episodes
+                .iter()
+                .position(|episode| episode["ep_id"].as_i64().unwrap_or(0) == ep_id)
+        })
+        .unwrap_or(0);
+    let episode = &result["episodes"][ep_index];
+    let season_id = result["season_id"].as_i64().unwrap_or(0);
+    let season = result["seasons"]
+        .as_array()
+        .and_then(|seasons| {
+            seasons
+                .iter()
+                .position(|s| s["season_id"].as_i64().unwrap_or(-1) == season_id)
+        })
+        .map(|i| i as u32 + 1)
+        .unwrap_or(0);
+    // 正片的title为集数，其他(PV等)使用位置
+    let episode_number = episode["title"]
+        .as_str()
+        .and_then(|t| t.parse::<u32>().ok())
+        .unwrap_or(ep_index as u32 + 1);
+    let series = result["series"]["series_title"]
+        .as_str()
+        .filter(|s| !s.is_empty())
+        .or(result["season_title"].as_str())
+        .unwrap_or("")
+        .to_string();
+    Metadata {
+        title: episode["share_copy"].as_str().unwrap_or("").to_string(),
+        artist: result["up_info"]["uname"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        date: format_date(episode["pub_time"].as_i64().unwrap_or(0)),
+        description: result["evaluate"].as_str().unwrap_or("").to_string(),
+        tags: result["styles"]
+            .as_array()
+            .unwrap_or(&Vec::new())
+            .iter()
+            .filter_map(|s| s.as_str())
+            .map(|s| s.to_string())
+            .collect(),
+        id: format!("ep{}", ep_id),
+        url: format!("https://www.bilibili.com/bangumi/play/ep{}", ep_id),
+        series,
+        season,
+        episode: episode_number,
+        cover: episode["cover"].as_str().unwrap_or("").to_string(),
+        ..Default::default()
+    }
+}
+
+/// 获取该ep_id对应的bvid和cid
+fn get_episode_ids(json: &Value, ep_id: &str) -> (String, String) {
+    let episode = json["result"]["episodes"]
+        .as_array()
+        .and_then(|episodes| {
+            episodes
+                .iter()
+                .find(|episode| episode["ep_id"].as_i64().unwrap_or(0).to_string() == ep_id)
+        })
+        .unwrap_or(&Value::Null);
+    (
+        episode["bvid"].as_str().unwrap_or("").to_string(),
+        episode["cid"].as_i64().unwrap_or(0).to_string(),
+    )
+}
+
 ///
 fn get_bangumi_pic(json: Value, ep_id: &str) -> String {
     let ep_id = ep_id.parse::<i64>().unwrap();
@@ -316,32 +1133,16 @@ pub fn remove_punctuation(input: &str) -> String {
         .collect()
 }
 
-pub async fn read_cookie_or_not(path: &Path) -> Result<Cookies> {
-    if path.exists() {
-        //println!("{:?} exists", path);
-        let mut file = File::open(path).await?;
-        let mut content = String::new();
-        file.read_to_string(&mut content).await?;
-        let cookie: Cookies = serde_json::from_str(&content)?;
-        return Ok(cookie);
-    } else {
-        println!("{:?} does not exist", path);
-    }
-    return Ok(Cookies {
-        SESSDATA: String::new(),
-        bili_jct: String::new(),
-        refresh_token: String::new(),
-    });
-}
-
 async fn down_season(
     ep_id_cp: String,
     client: &Client,
     headers: HeaderMap,
     name_response: Value,
     rsl: &str,
+    config: &Config,
 ) -> Result<()> {
-    let url_response = get_playurl(&client, &ep_id_cp, "", headers.clone(), rsl).await?;
+    let play_rsl = if config.audio_only { "ALL" } else { rsl };
+    let url_response = get_playurl(&client, &ep_id_cp, "", headers.clone(), play_rsl).await?;
     down_file_bangumi(
         url_response,
         name_response.clone(),
@@ -349,17 +1150,18 @@ async fn down_season(
         &client,
         headers.clone(),
         rsl,
+        config,
     )
     .await?;
     Ok(())
 }
 
 /// 下载番剧总函数
-async fn download_bangumi(ep_id: &str, season_id: &str, rsl: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let path = Path::new("./load");
+async fn download_bangumi(ep_id: &str, season_id: &str, rsl: &str, config: &Config) -> Result<()> {
+    let path = profile::cookie_path();
     let cookie = read_cookie_or_not(&path).await?;
-    let headers = create_headers(&cookie);
+    let client = create_client(&cookie)?;
+    let headers = create_headers();
     let name_response = get_bangumi_name(&client, &ep_id, &season_id, headers.clone()).await?;
     if season_id != "" {
         for i in 0..name_response["result"]["episodes"]
@@ -377,22 +1179,66 @@ async fn download_bangumi(ep_id: &str, season_id: &str, rsl: &str) -> Result<()>
                 headers.clone(),
                 name_response.clone(),
                 rsl,
+                config,
             )
             .await?;
         }
     } else {
-        let url_response = get_playurl(&client, &ep_id, "", headers.clone(), rsl).await?;
+        let play_rsl = if config.audio_only { "ALL" } else { rsl };
+        let url_response = get_playurl(&client, &ep_id, "", headers.clone(), play_rsl).await?;
         //println!("{:#}", url_response);
-        down_file_bangumi(url_response, name_response, ep_id, &client, headers, rsl).await?;
+        down_file_bangumi(
+            url_response,
+            name_response,
+            ep_id,
+            &client,
+            headers,
+            rsl,
+            config,
+        )
+        .await?;
     }
     Ok(())
 }
 
+/// 获取番剧的全部可用格式，不下载；未指定ep时取第一集
+pub async fn bangumi_media_info(ep_id: &str, season_id: &str) -> Result<MediaInfo> {
+    let path = profile::cookie_path();
+    let cookie = read_cookie_or_not(&path).await?;
+    let client = create_client(&cookie)?;
+    let headers = create_headers();
+    let name_response = get_bangumi_name(&client, ep_id, season_id, headers.clone()).await?;
+    let episode = name_response["result"]["episodes"]
+        .as_array()
+        .context("Missing episodes in season response")?
+        .iter()
+        .find(|episode| {
+            ep_id.is_empty() || episode["ep_id"].as_i64().unwrap_or(0).to_string() == ep_id
+        })
+        .context("Episode not found in season")?;
+    let ep_id = episode["ep_id"].as_i64().unwrap_or(0).to_string();
+    let cid = episode["cid"].as_i64().unwrap_or(0).to_string();
+    let bvid = episode["bvid"].as_str().unwrap_or("");
+    let title = remove_punctuation(&get_bangumi_name_from_json(name_response.clone(), &ep_id));
+
+    let url_response = get_playurl(&client, &ep_id, "", headers.clone(), "ALL").await?;
+    let (video, audio, duration) = parse_dash(&url_response["result"]["dash"]);
+    let player = get_player_info(&client, bvid, &cid, headers).await?;
+    Ok(MediaInfo {
+        title,
+        duration,
+        video,
+        audio,
+        subtitles: get_subtitle_langs(&player),
+        danmaku: cid != "0",
+    })
+}
+
 pub async fn bangumi_title(ep_id: &str, season_id: &str) -> Result<(String, String)> {
-    let client = reqwest::Client::new();
-    let path = Path::new("./load");
+    let path = profile::cookie_path();
     let cookie = read_cookie_or_not(&path).await?;
-    let headers = create_headers(&cookie);
+    let client = create_client(&cookie)?;
+    let headers = create_headers();
     let name_response = get_bangumi_name(&client, &ep_id, &season_id, headers.clone()).await?;
     let mut bangumi_name_temp = String::new();
     let mut bangumi_pic = String::new();
@@ -411,6 +1257,33 @@ pub async fn bangumi_title(ep_id: &str, season_id: &str) -> Result<(String, Stri
     }
 
     let bangumi_name = remove_punctuation(&bangumi_name_temp);
-    get_pic(&bangumi_pic).await?;
     Ok((bangumi_name, bangumi_pic))
 }
+
+#[tokio::test]
+async fn test_retry_keeps_streams() {
+    let dir = std::env::temp_dir();
+    let video = dir.join("test_retry_video.m4s");
+    let audio = dir.join("test_retry_audio.m4s");
+    std::fs::write(&video, b"video").unwrap();
+    std::fs::write(&audio, b"audio").unwrap();
+    let config = Config {
+        container: Container::Raw,
+        retries: 1,
+        ..Default::default()
+    };
+    let output = final_output("test_retry", &config);
+    assert_eq!(output, None);
+    let mut count = 0;
+    let result = retry("test", "test_retry", output.as_deref(), &config, || {
+        count += 1;
+        async { Err(anyhow::anyhow!("mux failed")) }
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(count, 2);
+    // raw的m4s就是输出，失败后保留以便重试
+    assert!(video.exists() && audio.exists());
+    let _ = std::fs::remove_file(&video);
+    let _ = std::fs::remove_file(&audio);
+}
@@ -1,26 +1,31 @@
-use crate::down_bangumi::{concat_video_audio, read_cookie_or_not, remove_punctuation};
-use crate::refresh_cookie::create_headers;
+use crate::chapter::parse_view_points;
+use crate::config::Config;
+use crate::down_bangumi::{down_audio, download_video_audio, remove_punctuation};
+use crate::inspect::{parse_dash, video_size, MediaInfo};
+use crate::metadata::{format_date, Metadata};
+use crate::profile;
+use crate::refresh_cookie::{create_client, create_headers, read_cookie_or_not};
 use crate::resolution;
+use crate::subtitle::parse_subtitle_list;
 use crate::wbi::get_wbi_keys_main;
 use anyhow::{Context, Ok, Result};
-use chrono::Utc;
-use futures_util::TryStreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::header::HeaderMap;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::{self, Value};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
-use tokio::io::AsyncWriteExt;
 
 #[derive(Deserialize, Debug)]
 struct BV {
     bv_id: String,
     cid: String,
     title: String,
+    owner: String,
+    pubdate: i64,
+    pic: String,
+    raw_title: String,
+    desc: String,
 }
 
 async fn get_bv_play_url(
@@ -64,6 +69,64 @@ async fn get_bv_play_url(
     Ok(json)
 }
 
+/// 获取播放器信息(字幕列表等)
+pub async fn get_player_info(
+    client: &Client,
+    bv_id: &str,
+    cid: &str,
+    headers: HeaderMap,
+) -> Result<Value> {
+    let url = "https://api.bilibili.com/x/player/v2";
+    let params: HashMap<&str, &str> = [("bvid", bv_id), ("cid", cid)].iter().cloned().collect();
+    let resp = client
+        .get(url)
+        .headers(headers)
+        .query(&params)
+        .send()
+        .await?
+        .text()
+        .await?;
+    let json: Value = serde_json::from_str(&resp)?;
+    Ok(json)
+}
+
+/// 按设置从播放器信息中获取看点章节和字幕列表，失败时只打印错误
+pub async fn fill_player_metadata(
+    client: &Client,
+    bv_id: &str,
+    cid: &str,
+    headers: HeaderMap,
+    metadata: &mut Metadata,
+    config: &Config,
+) {
+    if !config.chapters && !config.subtitles {
+        return;
+    }
+    let player = get_player_info(client, bv_id, cid, headers)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Error occurred: {}", e);
+            Value::Null
+        });
+    if config.chapters {
+        metadata.chapters = parse_view_points(&player);
+    }
+    if config.subtitles {
+        metadata.subtitles = parse_subtitle_list(&player);
+    }
+}
+
+/// 从播放器信息中获取字幕语言列表
+pub fn get_subtitle_langs(player: &Value) -> Vec<String> {
+    player["data"]["subtitle"]["subtitles"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|s| s["lan"].as_str())
+        .map(|s| s.to_string())
+        .collect()
+}
+
 async fn get_bv_cid_title(client: &Client, bv: &str, headers: HeaderMap) -> Result<BV> {
     let url = "https://api.bilibili.com/x/web-interface/wbi/view";
     let params: HashMap<&str, &str> = [("bvid", bv)].iter().cloned().collect();
@@ -84,15 +147,62 @@ async fn get_bv_cid_title(client: &Client, bv: &str, headers: HeaderMap) -> Resu
         .as_str()
         .unwrap_or("no title")
         .to_string();
+    let raw_title = title.clone();
     let title = remove_punctuation(&title);
     let bv = BV {
         bv_id: bv.to_string(),
         cid: cid,
         title: title,
+        raw_title,
+        desc: json["data"]["desc"].as_str().unwrap_or("").to_string(),
+        owner: json["data"]["owner"]["name"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        pubdate: json["data"]["pubdate"].as_i64().unwrap_or(0),
+        pic: json["data"]["pic"].as_str().unwrap_or("").to_string(),
     };
     Ok(bv)
 }
 
+/// 获取视频标签
+async fn get_bv_tags(client: &Client, bv: &str, headers: HeaderMap) -> Result<Vec<String>> {
+    let url = "https://api.bilibili.com/x/tag/archive/tags";
+    let params: HashMap<&str, &str> = [("bvid", bv)].iter().cloned().collect();
+    let resp = client
+        .get(url)
+        .headers(headers)
+        .query(&params)
+        .send()
+        .await?
+        .text()
+        .await?;
+    let json: Value = serde_json::from_str(&resp)?;
+    let tags = json["data"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|t| t["tag_name"].as_str())
+        .map(|t| t.to_string())
+        .collect();
+    Ok(tags)
+}
+
+fn bv_metadata(bv: &BV, tags: Vec<String>) -> Metadata {
+    Metadata {
+        title: bv.raw_title.clone(),
+        artist: bv.owner.clone(),
+        date: format_date(bv.pubdate),
+        description: bv.desc.clone(),
+        tags,
+        id: bv.bv_id.clone(),
+        url: format!("https://www.bilibili.com/video/{}", bv.bv_id),
+        cover: bv.pic.clone(),
+        cid: bv.cid.clone(),
+        ..Default::default()
+    }
+}
+
 fn get_bv_url(play_url: &Value, rsl: &str) -> Result<(String, String, i32)> {
     let qn: i32 = resolution::qn(rsl).parse().unwrap();
     let video_index = play_url["data"]["dash"]["video"]
@@ -130,41 +240,18 @@ fn get_bv_url(play_url: &Value, rsl: &str) -> Result<(String, String, i32)> {
     Ok((video_url, audio_url, qn))
 }
 
-async fn down_file_url(url: &str, client: Client, headers: HeaderMap, path: &str) -> Result<()> {
-    let resp = client
-        .get(url)
-        .headers(headers.clone())
-        .send()
-        .await
-        .context("Failed to download stream")?;
-    let total_size = resp.content_length().unwrap_or(0);
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-            .progress_chars("=> "),
-    );
-    let mut file = File::create(&path)?;
-    let mut stream = resp.bytes_stream();
-    while let Some(chunk) = stream.try_next().await? {
-        let chunk = chunk;
-        file.write_all(&chunk)?;
-        pb.inc(chunk.len() as u64);
-    }
-    pb.finish_with_message("Downloaded video stream");
-    Ok(())
-}
-
 async fn down_file_bv_(
     client: &Client,
     url: Value,
     name: String,
     headers: HeaderMap,
     rsl: &str,
-    bv_id: &str,
+    mut metadata: Metadata,
+    config: &Config,
 ) -> Result<()> {
     let (video_url, audio_url, qn) =
         get_bv_url(&url, rsl).unwrap_or((String::new(), String::new(), 0));
+    (metadata.width, metadata.height) = video_size(&url["data"]["dash"], &video_url);
 
     let qn_c = resolution::qn(rsl);
     if qn != qn_c.parse::<i32>().unwrap() {
@@ -178,67 +265,105 @@ async fn down_file_bv_(
     }
 
     let name = format!("{} {}", name, rsl);
-    let video_path = format!("./download/{}_video.m4s", name);
-    let audio_path = format!("./download/{}_audio.m4s", name);
-    let output_path = format!("./download/{}.mp4", name);
-
-    let time = Utc::now() + chrono::Duration::hours(8);
-    let time_ = time.format("%Y-%m-%d %H:%M:%S");
-    let data = format!("{}\t{}\t{}\t\n", time_, bv_id, name);
-    let path = Path::new("dat.log");
-    if !path.exists() {
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(data.as_bytes()).await?;
-    } else {
-        let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
-            .open(path)
-            .await?;
-        file.write_all(data.as_bytes()).await?;
-    }
-
-    if Path::new(&output_path).exists() {
-        println!("{} already exists", output_path);
-        return Ok(());
-    }
-    println!("downloading {}", name);
-
-    let urls = vec![(video_url, video_path), (audio_url, audio_path)];
-    for (url, path) in urls {
-        down_file_url(&url, client.clone(), headers.clone(), &path).await?;
-    }
-    concat_video_audio(name.clone()).await?;
-    println!("Concat completed for {}", name);
-    Ok(())
+    let urls = [video_url.as_str(), audio_url.as_str()];
+    download_video_audio(client, headers, urls, &name, &metadata, config).await
 }
 
-async fn bv_down_main(bv_id: &str, rsl: &str) -> Result<String> {
-    let client = reqwest::Client::new();
-    let path = Path::new("load");
-    let cookies = read_cookie_or_not(path).await?;
-    let headers = create_headers(&cookies);
+async fn bv_down_main(bv_id: &str, rsl: &str, config: &Config) -> Result<String> {
+    let path = profile::cookie_path();
+    let cookies = read_cookie_or_not(&path).await?;
+    let client = create_client(&cookies)?;
+    let headers = create_headers();
     let bv = get_bv_cid_title(&client, bv_id, headers.clone())
         .await
         .context("Failed to get bv cid title")?;
     println!("{:#?}", bv);
+    let tags = get_bv_tags(&client, &bv.bv_id, headers.clone())
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Error occurred: {}", e);
+            Vec::new()
+        });
+    let mut metadata = bv_metadata(&bv, tags);
+    fill_player_metadata(
+        &client,
+        &bv.bv_id,
+        &bv.cid,
+        headers.clone(),
+        &mut metadata,
+        config,
+    )
+    .await;
+
+    if config.audio_only {
+        let play_url = get_bv_play_url(&client, &bv.bv_id, &bv.cid, headers.clone(), "ALL")
+            .await
+            .context("Failed to get bv play url")?;
+        metadata.duration = play_url["data"]["dash"]["duration"].as_u64().unwrap_or(0);
+        down_audio(
+            &client,
+            &play_url["data"]["dash"],
+            &bv.title,
+            &metadata,
+            headers,
+            &bv.bv_id,
+            config,
+        )
+        .await?;
+        return Ok(bv.title);
+    }
 
     let play_url = get_bv_play_url(&client, &bv.bv_id, &bv.cid, headers.clone(), rsl)
         .await
         .context("Failed to get bv play url")?;
-    down_file_bv_(&client, play_url, bv.title.clone(), headers, rsl, &bv.bv_id).await?;
+    metadata.duration = play_url["data"]["dash"]["duration"].as_u64().unwrap_or(0);
+    down_file_bv_(
+        &client,
+        play_url,
+        bv.title.clone(),
+        headers,
+        rsl,
+        metadata,
+        config,
+    )
+    .await?;
     Ok(bv.title)
 }
 
-pub async fn down_main(bv_id: &str, rsl: &str) -> Result<String> {
-    let title = bv_down_main(bv_id, rsl).await?;
+pub async fn down_main(bv_id: &str, rsl: &str, config: &Config) -> Result<String> {
+    let title = bv_down_main(bv_id, rsl, config).await?;
     Ok(title)
 }
 
+/// 获取视频的全部可用格式，不下载
+pub async fn bv_media_info(bv_id: &str) -> Result<MediaInfo> {
+    let path = profile::cookie_path();
+    let cookies = read_cookie_or_not(&path).await?;
+    let client = create_client(&cookies)?;
+    let headers = create_headers();
+    let bv = get_bv_cid_title(&client, bv_id, headers.clone())
+        .await
+        .context("Failed to get bv cid title")?;
+    let play_url = get_bv_play_url(&client, &bv.bv_id, &bv.cid, headers.clone(), "ALL")
+        .await
+        .context("Failed to get bv play url")?;
+    let (video, audio, duration) = parse_dash(&play_url["data"]["dash"]);
+    let player = get_player_info(&client, &bv.bv_id, &bv.cid, headers).await?;
+    Ok(MediaInfo {
+        title: bv.title,
+        duration,
+        video,
+        audio,
+        subtitles: get_subtitle_langs(&player),
+        danmaku: !bv.cid.is_empty(),
+    })
+}
+
 pub async fn bv_title(bv_id: &str) -> Result<(String, String)> {
-    let client = reqwest::Client::new();
-    let path = Path::new("load");
-    let cookies = read_cookie_or_not(path).await?;
-    let headers = create_headers(&cookies);
+    let path = profile::cookie_path();
+    let cookies = read_cookie_or_not(&path).await?;
+    let client = create_client(&cookies)?;
+    let headers = create_headers();
     let url = "https://api.bilibili.com/x/web-interface/wbi/view";
     let params: HashMap<&str, &str> = [("bvid", bv_id)].iter().cloned().collect();
     let resp = client
@@ -256,19 +381,75 @@ pub async fn bv_title(bv_id: &str) -> Result<(String, String)> {
         .to_string();
     let pic = json["data"]["pic"].as_str().unwrap_or("no pic").to_string();
     let title = remove_punctuation(&title);
-    get_pic(&pic).await?;
     Ok((title, pic))
 }
 
-pub async fn get_pic(pic: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let path = Path::new("load");
-    let cookies = read_cookie_or_not(path).await?;
-    let headers = create_headers(&cookies);
+/// 获取封面图片数据，用于界面预览
+pub async fn get_pic(pic: &str) -> Result<Vec<u8>> {
+    let path = profile::cookie_path();
+    let cookies = read_cookie_or_not(&path).await?;
+    let client = create_client(&cookies)?;
+    let headers = create_headers();
     let resp = client.get(pic).headers(headers).send().await?;
+    let bytes = resp.error_for_status()?.bytes().await?;
+    Ok(bytes.to_vec())
+}
+
+/// 根据Content-Type判断图片扩展名，未知时使用地址中的扩展名
+fn image_ext(content_type: &str, url: &str) -> String {
+    match content_type {
+        "image/jpeg" => "jpg".to_string(),
+        "image/png" => "png".to_string(),
+        "image/webp" => "webp".to_string(),
+        "image/gif" => "gif".to_string(),
+        _ => url
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+            .filter(|ext| ext.len() <= 4 && !ext.contains('/'))
+            .unwrap_or_else(|| "jpg".to_string()),
+    }
+}
+
+/// 保存封面到视频旁，返回文件路径；不保存原图时请求1280宽的jpg
+pub async fn save_cover(
+    client: &Client,
+    url: &str,
+    name: &str,
+    original: bool,
+    headers: HeaderMap,
+) -> Result<String> {
+    if url.is_empty() {
+        return Err(anyhow::anyhow!("No cover for {}", name));
+    }
+    let url = if original {
+        url.to_string()
+    } else {
+        format!("{}@1280w.jpg", url)
+    };
+    let resp = client
+        .get(&url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
     let bytes = resp.bytes().await?;
-    let path = Path::new("pic.png");
-    let mut file = tokio::fs::File::create(path).await?;
-    file.write_all(&bytes).await?;
-    Ok(())
+    let path = format!("./download/{}.{}", name, image_ext(&content_type, &url));
+    tokio::fs::write(&path, &bytes).await?;
+    Ok(path)
+}
+
+#[test]
+fn test_image_ext() {
+    assert_eq!(image_ext("image/png", "http://i0.hdslb.com/a.jpg"), "png");
+    assert_eq!(
+        image_ext("", "http://i0.hdslb.com/bfs/archive/a.JPG"),
+        "jpg"
+    );
+    assert_eq!(image_ext("", "http://i0.hdslb.com/bfs/archive/a"), "jpg");
 }
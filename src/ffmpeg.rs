@@ -0,0 +1,145 @@
+use crate::progress;
+use anyhow::{anyhow, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+/// 检查ffmpeg是否可用，返回版本号
+pub async fn check_ffmpeg(path: &str) -> Result<String> {
+    let output = Command::new(path)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to execute ffmpeg at {}, please install FFmpeg or set its path",
+                path
+            )
+        })?;
+    if !output.status.success() {
+        return Err(anyhow!("{} -version exited with {}", path, output.status));
+    }
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("{} does not look like ffmpeg", path))
+}
+
+/// 解析 `ffmpeg version 6.1.1 Copyright ...` 中的版本号
+fn parse_version(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .next()?
+        .strip_prefix("ffmpeg version ")?
+        .split_whitespace()
+        .next()
+        .map(|v| v.to_string())
+}
+
+/// 运行ffmpeg，失败时返回包含stderr的错误
+///
+/// duration为输入时长(秒)，大于0时通过 -progress 读取进度，显示进度条
+pub async fn run_ffmpeg(path: &str, args: &[String], stage: &str, duration: u64) -> Result<()> {
+    let mut command = Command::new(path);
+    if duration > 0 {
+        command.args(["-progress", "pipe:1", "-nostats"]);
+    }
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute ffmpeg at {}", path))?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("Failed to capture ffmpeg stderr")?;
+    let stderr = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).await.map(|_| buf)
+    });
+
+    let total = duration * 1_000_000;
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}% {msg}")?
+            .progress_chars("=> "),
+    );
+    pb.set_message(stage.to_string());
+    progress::start(stage, total);
+    let mut lines = BufReader::new(
+        child
+            .stdout
+            .take()
+            .context("Failed to capture ffmpeg stdout")?,
+    )
+    .lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(time) = parse_progress(&line) {
+            pb.set_position(time.min(total));
+            progress::set_position(time);
+        }
+    }
+
+    let status = child.wait().await?;
+    let stderr = stderr.await??;
+    if !status.success() {
+        pb.abandon();
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(anyhow!("ffmpeg exited with {}: {}", status, stderr.trim()));
+    }
+    pb.finish();
+    progress::finish();
+    Ok(())
+}
+
+/// 读取媒体文件的时长(秒)，用于不能直接解析的格式(mkv/flac)
+pub async fn probe_duration(path: &str, file: &str) -> Result<f64> {
+    // 没有输出文件时ffmpeg会以错误退出，只需要stderr中的输入信息
+    let output = Command::new(path)
+        .args(["-hide_banner", "-i", file])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Failed to execute ffmpeg at {}", path))?;
+    parse_duration(&String::from_utf8_lossy(&output.stderr))
+        .ok_or_else(|| anyhow!("Failed to read duration of {}", file))
+}
+
+/// 解析 `Duration: 00:01:02.50, start: ...` 中的时长
+fn parse_duration(stderr: &str) -> Option<f64> {
+    let time = stderr.split("Duration: ").nth(1)?.split(',').next()?;
+    let mut seconds = 0.0;
+    for part in time.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// 解析 -progress 输出中的 `out_time_us=`，单位微秒
+fn parse_progress(line: &str) -> Option<u64> {
+    line.strip_prefix("out_time_us=")?.trim().parse().ok()
+}
+
+#[test]
+fn test_parse_version() {
+    let stdout = "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers\nbuilt with gcc";
+    assert_eq!(parse_version(stdout), Some("6.1.1-3ubuntu5".to_string()));
+    assert_eq!(parse_version("ffprobe version 6.1"), None);
+}
+
+#[test]
+fn test_parse_progress() {
+    assert_eq!(parse_progress("out_time_us=12500000"), Some(12500000));
+    assert_eq!(parse_progress("out_time_us=N/A"), None);
+    assert_eq!(parse_progress("progress=continue"), None);
+}
+
+#[test]
+fn test_parse_duration() {
+    let stderr = "Input #0, matroska,webm, from 'a.mkv':\n  Duration: 01:02:03.50, start: 0.000000, bitrate: 1 kb/s";
+    assert_eq!(parse_duration(stderr), Some(3723.5));
+    assert_eq!(parse_duration("  Duration: N/A, bitrate: N/A"), None);
+}
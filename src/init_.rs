@@ -1,5 +1,10 @@
+use crate::account;
+use crate::config::Config;
 use crate::down_bangumi;
 use crate::down_bv;
+use crate::inspect::MediaInfo;
+use crate::profile;
+use crate::refresh_cookie;
 use anyhow::{Context, Result};
 
 #[derive(Debug)]
@@ -59,12 +64,27 @@ pub fn get_epid_season(url: &str) -> Result<Video> {
     }
 }
 
-pub async fn choose_download_method(video: &Video, rsl: &str) -> Result<String> {
+pub async fn choose_download_method(video: &Video, rsl: &str, config: &Config) -> Result<String> {
+    // 刷新失败时仍使用原来的cookie下载
+    if let Err(e) = refresh_cookie::refresh_cookie(&profile::cookie_path()).await {
+        eprintln!("Failed to refresh cookie: {}", e);
+    }
+    // 清晰度超出账号权限时接口会返回较低的清晰度，下载前提示
+    if !config.audio_only {
+        match account::load_account(&profile::cookie_path()).await {
+            Ok(account) => {
+                if let Some(warning) = account.quality_warning(rsl) {
+                    eprintln!("Warning: {}", warning);
+                }
+            }
+            Err(e) => eprintln!("Failed to get account: {}", e),
+        }
+    }
     let mut title = String::new();
     if !video.ep_id.is_empty() || !video.season_id.is_empty() {
-        down_bangumi::down_main((&video.ep_id, &video.season_id), rsl).await?;
+        down_bangumi::down_main((&video.ep_id, &video.season_id), rsl, config).await?;
     } else if !video.bv_id.is_empty() {
-        title = down_bv::down_main(&video.bv_id, rsl).await?;
+        title = down_bv::down_main(&video.bv_id, rsl, config).await?;
     } else {
         Err(anyhow::anyhow!("No valid video ID found"))?;
     }
@@ -83,3 +103,13 @@ pub async fn get_title_pic(video: &Video) -> Result<(String, String)> {
     }
     Ok((title, pic))
 }
+
+pub async fn get_media_info(video: &Video) -> Result<MediaInfo> {
+    if !video.ep_id.is_empty() || !video.season_id.is_empty() {
+        down_bangumi::bangumi_media_info(&video.ep_id, &video.season_id).await
+    } else if !video.bv_id.is_empty() {
+        down_bv::bv_media_info(&video.bv_id).await
+    } else {
+        Err(anyhow::anyhow!("No valid video ID found"))
+    }
+}
@@ -0,0 +1,161 @@
+use crate::resolution;
+use serde::Serialize;
+use serde_json::Value;
+
+/// 单条DASH流信息
+#[derive(Serialize, Debug, Clone)]
+pub struct Stream {
+    pub id: i64,
+    pub label: String,
+    pub codecs: String,
+    pub width: i64,
+    pub height: i64,
+    pub frame_rate: String,
+    pub bandwidth: u64,
+    /// 按码率和时长估算的大小(字节)
+    pub size: u64,
+}
+
+/// 视频可下载的格式信息
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MediaInfo {
+    pub title: String,
+    pub duration: u64,
+    pub video: Vec<Stream>,
+    pub audio: Vec<Stream>,
+    pub subtitles: Vec<String>,
+    pub danmaku: bool,
+}
+
+fn parse_stream(v: &Value, duration: u64, audio: bool) -> Stream {
+    let id = v["id"].as_i64().unwrap_or(0);
+    let id_str = id.to_string();
+    let label = if audio {
+        resolution::audio(&id_str)
+    } else {
+        resolution::rsl(&id_str)
+    };
+    let bandwidth = v["bandwidth"].as_u64().unwrap_or(0);
+    Stream {
+        id,
+        label: label.to_string(),
+        codecs: v["codecs"].as_str().unwrap_or("").to_string(),
+        width: v["width"].as_i64().unwrap_or(0),
+        height: v["height"].as_i64().unwrap_or(0),
+        frame_rate: v["frameRate"].as_str().unwrap_or("").to_string(),
+        bandwidth,
+        size: bandwidth * duration / 8,
+    }
+}
+
+/// 按地址查找视频流的分辨率，找不到时为(0, 0)
+pub fn video_size(dash: &Value, url: &str) -> (u32, u32) {
+    dash["video"]
+        .as_array()
+        .and_then(|video| video.iter().find(|v| v["baseUrl"] == url))
+        .map(|v| {
+            (
+                v["width"].as_u64().unwrap_or(0) as u32,
+                v["height"].as_u64().unwrap_or(0) as u32,
+            )
+        })
+        .unwrap_or((0, 0))
+}
+
+/// 解析playurl返回的dash字段，得到全部视频流和音频流
+pub fn parse_dash(dash: &Value) -> (Vec<Stream>, Vec<Stream>, u64) {
+    let duration = dash["duration"].as_u64().unwrap_or(0);
+    let video = dash["video"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|v| parse_stream(v, duration, false))
+        .collect();
+    let mut audio: Vec<Stream> = dash["audio"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|a| parse_stream(a, duration, true))
+        .collect();
+    // 杜比全景声和无损音轨不在audio数组中
+    for a in dash["dolby"]["audio"].as_array().unwrap_or(&Vec::new()) {
+        audio.push(parse_stream(a, duration, true));
+    }
+    if dash["flac"]["audio"].is_object() {
+        audio.push(parse_stream(&dash["flac"]["audio"], duration, true));
+    }
+    (video, audio, duration)
+}
+
+/// 字节数转换为可读大小
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = size as f64;
+    let mut i = 0;
+    while size >= 1024.0 && i < units.len() - 1 {
+        size /= 1024.0;
+        i += 1;
+    }
+    format!("{:.1} {}", size, units[i])
+}
+
+/// 以表格形式打印格式信息
+pub fn print_info(info: &MediaInfo) {
+    println!("{}", info.title);
+    println!("时长: {}s", info.duration);
+    println!("视频流:");
+    println!(
+        "  {:<5} {:<12} {:<16} {:<10} {:<8} {:>10} {:>11}",
+        "id", "label", "codecs", "size", "fps", "bandwidth", "estimated"
+    );
+    for v in &info.video {
+        println!(
+            "  {:<5} {:<12} {:<16} {:<10} {:<8} {:>10} {:>11}",
+            v.id,
+            v.label,
+            v.codecs,
+            format!("{}x{}", v.width, v.height),
+            v.frame_rate,
+            v.bandwidth,
+            format_size(v.size)
+        );
+    }
+    println!("音频流:");
+    for a in &info.audio {
+        println!(
+            "  {:<5} {:<12} {:<16} {:>10} {:>11}",
+            a.id,
+            a.label,
+            a.codecs,
+            a.bandwidth,
+            format_size(a.size)
+        );
+    }
+    if info.subtitles.is_empty() {
+        println!("字幕: 无");
+    } else {
+        println!("字幕: {}", info.subtitles.join(", "));
+    }
+    println!("弹幕: {}", if info.danmaku { "有" } else { "无" });
+}
+
+#[test]
+fn test_parse_dash() {
+    let dash: Value = serde_json::from_str(
+        r#"{
+            "duration": 100,
+            "video": [{"id": 80, "codecs": "avc1.640032", "width": 1920, "height": 1080,
+                       "frameRate": "30.000", "bandwidth": 800000}],
+            "audio": [{"id": 30280, "codecs": "mp4a.40.2", "bandwidth": 192000}],
+            "dolby": {"audio": null},
+            "flac": {"audio": {"id": 30251, "codecs": "fLaC", "bandwidth": 900000}}
+        }"#,
+    )
+    .unwrap();
+    let (video, audio, duration) = parse_dash(&dash);
+    assert_eq!(duration, 100);
+    assert_eq!(video[0].label, "1080P");
+    assert_eq!(video[0].size, 10_000_000);
+    assert_eq!(audio.len(), 2);
+    assert_eq!(audio[1].label, "Hi-Res");
+}
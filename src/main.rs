@@ -1,22 +1,53 @@
+#[macro_use]
+mod named;
 mod qrcode_login;
-use crate::qrcode_login::login_qrcode;
+use crate::account::Account;
+use crate::burn::{BurnEncoder, BurnQuality, BurnSource};
+use crate::config::{Config, Container};
+use crate::danmaku::DanmakuProfile;
+use crate::inspect::MediaInfo;
+use crate::qrcode_login::PollState;
+use crate::subtitle::SubtitleFormat;
 use anyhow::{Context, Result};
 use core::f32;
-use reqwest::Client;
+use reqwest::cookie::Jar;
 use std::sync::Arc;
-use std::{io, path::Path, result};
+use std::{io, result};
 use tokio::sync::Mutex;
+mod account;
+mod burn;
+mod chapter;
+mod cli;
+mod config;
+mod danmaku;
 mod down_bangumi;
 mod down_bv;
+mod ffmpeg;
 mod init_;
+mod inspect;
+mod metadata;
+mod profile;
+mod progress;
 mod refresh_cookie;
+mod remux;
+mod verify;
 mod wbi;
 use eframe::egui;
 use eframe::egui::{ComboBox, FontDefinitions, FontFamily, ProgressBar, Vec2};
 mod resolution;
+mod subtitle;
 
 #[tokio::main]
 async fn main() -> Result<(), eframe::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    profile::set_active(&config::load_config().profile);
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("Error occurred: {}", e);
+        }
+        return Ok(());
+    }
+
     let size = egui::ViewportBuilder {
         min_inner_size: Some(egui::Vec2::new(500.0, 400.0)),
         max_inner_size: Some(egui::Vec2::new(500.0, 400.0)),
@@ -38,7 +69,7 @@ async fn main() -> Result<(), eframe::Error> {
 impl MyApp {
     fn new(_cc: &eframe::CreationContext<'_>) -> Self {
         load_fonts(&_cc.egui_ctx);
-        Self {
+        let mut app = Self {
             current_view: View::MainMenu,
             url: String::new(),
             selected_resolution: String::new(),
@@ -53,35 +84,174 @@ impl MyApp {
                 "360P".to_string(),
             ],
             info: String::new(),
-            pic: false,
-            mutex_login: Arc::new(Mutex::new(false)),
+            cover: None,
+            login_open: false,
+            login_task: None,
+            login_texture: None,
+            mutex_login: Arc::new(Mutex::new(LoginStatus::default())),
+            mutex_account: Arc::new(Mutex::new(None)),
+            new_profile: String::new(),
             mutex_info: Arc::new(Mutex::new(String::new())),
+            mutex_cover: Arc::new(Mutex::new(None)),
+            mutex_media: Arc::new(Mutex::new(None)),
+            mutex_ffmpeg: Arc::new(Mutex::new(String::new())),
             save_path: "./download".to_string(),
-        }
+            config: config::load_config(),
+        };
+        app.load_account();
+        app
     }
     fn update_info(&mut self, info: String) {
         self.info = info;
     }
-    fn update_pic(&mut self, pic: bool) {
-        self.pic = pic;
+    /// 收到新的封面数据时生成纹理
+    fn update_cover(&mut self, ctx: &egui::Context) {
+        let bytes = {
+            let cover = self.mutex_cover.clone();
+            tokio::task::block_in_place(|| {
+                let mut lock = futures::executor::block_on(cover.lock());
+                lock.take()
+            })
+        };
+        let Some(bytes) = bytes else {
+            return;
+        };
+        match image::load_from_memory(&bytes) {
+            Ok(img) => {
+                let img = img.to_rgba8();
+                let size = [img.width() as usize, img.height() as usize];
+                let color = egui::ColorImage::from_rgba_unmultiplied(size, img.as_raw());
+                self.cover = Some(ctx.load_texture("cover", color, Default::default()));
+            }
+            Err(e) => eprintln!("Error occurred: {}", e),
+        }
     }
+    /// 后台查询当前cookie对应的账号
+    fn load_account(&mut self) {
+        let mutex_account = Arc::clone(&self.mutex_account);
+        tokio::spawn(async move {
+            *mutex_account.lock().await = None;
+            match account::load_account(&profile::cookie_path()).await {
+                Ok(account) => *mutex_account.lock().await = Some(account),
+                Err(e) => eprintln!("Error occurred: {}", e),
+            }
+        });
+    }
+    /// 切换使用的账号并保存为默认账号，与命令行的 profile use 一致
+    fn switch_profile(&mut self, name: &str) {
+        profile::set_active(name);
+        self.config.profile = name.to_string();
+        // 只写入账号，不保存设置页中未保存的修改
+        let mut saved = config::load_config();
+        saved.profile = name.to_string();
+        if let Err(e) = config::save_config(&saved) {
+            eprintln!("Error occurred: {}", e);
+        }
+        self.load_account();
+    }
+    /// 打开登录窗口并申请新的二维码，之前的轮询任务会被取消
     fn login(&mut self) {
-        println!("登录按钮点击{:?}", self.mutex_login);
+        println!("登录按钮点击");
+        if let Some(task) = self.login_task.take() {
+            task.abort();
+        }
+        self.login_open = true;
+        self.login_texture = None;
         let mutex_login = Arc::clone(&self.mutex_login);
-        tokio::spawn(async move {
-            let mut mutex_login = match mutex_login.try_lock() {
-                Ok(guard) => guard,
-                Err(_) => {
-                    println!("Failed to acquire login lock");
-                    return;
+        let mutex_account = Arc::clone(&self.mutex_account);
+        self.login_task = Some(tokio::spawn(async move {
+            *mutex_login.lock().await = LoginStatus::Generating;
+            let status = match login(&mutex_login).await {
+                Ok(status) => status,
+                Err(e) => {
+                    eprintln!("Error occurred: {}", e);
+                    LoginStatus::Failed(format!("{:#}", e))
                 }
             };
-            if !*mutex_login {
-                *mutex_login = true;
-                login().await;
-                *mutex_login = false;
+            if let LoginStatus::Success(account) = &status {
+                *mutex_account.lock().await = Some(account.clone());
             }
-        });
+            *mutex_login.lock().await = status;
+        }));
+    }
+    /// 扫码登录窗口，显示二维码和轮询状态
+    fn login_window(&mut self, ctx: &egui::Context) {
+        if !self.login_open {
+            return;
+        }
+        let status = {
+            let status = self.mutex_login.clone();
+            tokio::task::block_in_place(|| {
+                let lock = futures::executor::block_on(status.lock());
+                lock.clone()
+            })
+        };
+        // 二维码内容变化时重新生成纹理
+        if let LoginStatus::Waiting { url, .. } = &status {
+            if self.login_texture.as_ref().map(|(u, _)| u) != Some(url) {
+                match qrcode_login::render_gray(url, 4) {
+                    Ok((side, pixels)) => {
+                        let image = egui::ColorImage::from_gray([side, side], &pixels);
+                        let texture =
+                            ctx.load_texture("login_qrcode", image, egui::TextureOptions::NEAREST);
+                        self.login_texture = Some((url.clone(), texture));
+                    }
+                    Err(e) => eprintln!("Error occurred: {}", e),
+                }
+            }
+        }
+        let mut open = true;
+        let mut regenerate = false;
+        egui::Window::new("扫码登录")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.vertical_centered(|ui| match &status {
+                    LoginStatus::Generating => {
+                        ui.spinner();
+                        ui.label("正在获取二维码");
+                    }
+                    LoginStatus::Waiting { state, .. } => {
+                        if let Some((_, texture)) = &self.login_texture {
+                            ui.add(
+                                egui::Image::new(egui::load::SizedTexture::from_handle(texture))
+                                    .max_width(200.0),
+                            );
+                        }
+                        ui.label(state.describe());
+                        if *state == PollState::Expired {
+                            regenerate = ui.button("重新获取二维码").clicked();
+                        } else {
+                            ui.label("请使用哔哩哔哩手机客户端扫码登录");
+                        }
+                    }
+                    LoginStatus::Success(account) => {
+                        ui.label("登录成功");
+                        ui.label(format!("当前账号: {}", account.describe()));
+                    }
+                    LoginStatus::Failed(e) => {
+                        ui.label(format!("登录失败: {}", e));
+                        regenerate = ui.button("重新获取二维码").clicked();
+                    }
+                });
+            });
+        if regenerate {
+            self.login();
+        } else if !open {
+            // 关闭窗口时停止轮询
+            if let Some(task) = self.login_task.take() {
+                task.abort();
+            }
+            self.login_open = false;
+            self.login_texture = None;
+        } else if matches!(
+            status,
+            LoginStatus::Generating | LoginStatus::Waiting { .. }
+        ) {
+            ctx.request_repaint_after(std::time::Duration::from_millis(500));
+        }
     }
     fn handle_down(&mut self) {
         println!("下载按钮点击");
@@ -95,20 +265,28 @@ impl MyApp {
         };
         println!("{:?}", video);
         let mutex_info = Arc::clone(&self.mutex_info);
+        let mutex_cover = Arc::clone(&self.mutex_cover);
+        self.cover = None;
+        let config = self.config.clone();
         let mut rsl = self.selected_resolution.clone();
         if self.selected_resolution.len() == 0 {
             rsl = "4K".to_string();
         }
         tokio::spawn(async move {
             match init_::get_title_pic(&video).await {
-                Ok((t, _)) => {
+                Ok((t, pic)) => {
                     let mut lock_t = mutex_info.lock().await;
                     *lock_t = t;
+                    drop(lock_t);
+                    match down_bv::get_pic(&pic).await {
+                        Ok(bytes) => *mutex_cover.lock().await = Some(bytes),
+                        Err(e) => eprintln!("Error occurred: {}", e),
+                    }
                 }
                 Err(e) => eprintln!("Error occurred: {}", e),
             }
 
-            let result = init_::choose_download_method(&video, &rsl).await;
+            let result = init_::choose_download_method(&video, &rsl, &config).await;
             match result {
                 Ok(title) => {
                     println!("Download completed for {}", title);
@@ -119,12 +297,58 @@ impl MyApp {
         //let x = &self.mutex_info;
         //println!("aaa{:?}", x);
     }
+    fn check_ffmpeg(&mut self) {
+        let path = self.config.ffmpeg_path.clone();
+        let mutex_ffmpeg = Arc::clone(&self.mutex_ffmpeg);
+        tokio::spawn(async move {
+            let status = match ffmpeg::check_ffmpeg(&path).await {
+                Ok(version) => format!("ffmpeg {}", version),
+                Err(e) => format!("{:#}", e),
+            };
+            *mutex_ffmpeg.lock().await = status;
+        });
+    }
+    fn handle_inspect(&mut self) {
+        println!("格式按钮点击");
+        let video = match init_::get_epid_season(&self.url) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Error occurred: {}", e);
+                return;
+            }
+        };
+        let mutex_media = Arc::clone(&self.mutex_media);
+        tokio::spawn(async move {
+            *mutex_media.lock().await = None;
+            match init_::get_media_info(&video).await {
+                Ok(info) => *mutex_media.lock().await = Some(info),
+                Err(e) => eprintln!("Error occurred: {}", e),
+            }
+        });
+        self.current_view = View::Inspect;
+    }
 }
 
 enum View {
     MainMenu,
     Settings,
     About,
+    Inspect,
+}
+
+/// 扫码登录的进度，由轮询任务更新
+#[derive(Debug, Clone, Default)]
+enum LoginStatus {
+    #[default]
+    Generating,
+    /// 二维码内容和最近一次轮询的状态
+    Waiting {
+        url: String,
+        state: PollState,
+    },
+    /// 登录的账号
+    Success(Account),
+    Failed(String),
 }
 
 impl Default for View {
@@ -140,10 +364,19 @@ struct MyApp {
     selected_resolution: String,
     resolutions: Vec<String>,
     info: String,
-    pic: bool,
-    mutex_login: Arc<Mutex<bool>>,
+    cover: Option<egui::TextureHandle>,
+    login_open: bool,
+    login_task: Option<tokio::task::JoinHandle<()>>,
+    login_texture: Option<(String, egui::TextureHandle)>,
+    mutex_login: Arc<Mutex<LoginStatus>>,
+    mutex_account: Arc<Mutex<Option<Account>>>,
+    new_profile: String,
     mutex_info: Arc<Mutex<String>>,
+    mutex_cover: Arc<Mutex<Option<Vec<u8>>>>,
+    mutex_media: Arc<Mutex<Option<MediaInfo>>>,
+    mutex_ffmpeg: Arc<Mutex<String>>,
     save_path: String,
+    config: Config,
 }
 
 impl eframe::App for MyApp {
@@ -158,6 +391,13 @@ impl eframe::App for MyApp {
                 let image_size = Vec2::new(available_size.x * 0.2, available_size.x * 0.2);
                 let info_width = available_size.x * 0.6;
                 let info_height = available_size.y * 0.1;
+                let account = {
+                    let account = self.mutex_account.clone();
+                    tokio::task::block_in_place(|| {
+                        let lock = futures::executor::block_on(account.lock());
+                        lock.clone()
+                    })
+                };
 
                 ui.horizontal(|ui| {
                     if ui.button("设置").clicked() {
@@ -166,6 +406,12 @@ impl eframe::App for MyApp {
                     if ui.button("关于").clicked() {
                         self.current_view = View::About;
                     }
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        match &account {
+                            Some(account) => ui.label(account.describe()),
+                            None => ui.label("正在查询账号"),
+                        };
+                    });
                 });
                 ui.separator();
 
@@ -185,11 +431,26 @@ impl eframe::App for MyApp {
                         .clicked()
                     {
                         println!("登出按钮点击");
-                        match std::fs::remove_file("load") {
+                        // 删除当前账号的cookie，其他账号切换回默认账号
+                        match profile::remove(&profile::active()) {
                             Ok(_) => println!("delogin successful"),
                             Err(e) => eprintln!("Error occurred: {}", e),
                         }
+                        self.switch_profile(&profile::active());
                     }
+                    ui.label("账号");
+                    let active = profile::active();
+                    ui.add_enabled_ui(!self.login_open, |ui| {
+                        egui::ComboBox::new(egui::Id::new("profile_select"), "")
+                            .selected_text(&active)
+                            .show_ui(ui, |ui| {
+                                for name in profile::list() {
+                                    if ui.selectable_label(name == active, &name).clicked() {
+                                        self.switch_profile(&name);
+                                    }
+                                }
+                            });
+                    });
                 });
 
                 ui.separator();
@@ -222,7 +483,37 @@ impl eframe::App for MyApp {
                         println!("分辨率:{}", self.selected_resolution);
                         self.handle_down();
                     }
+                    if ui
+                        .add_sized(button_size, egui::Button::new("格式"))
+                        .clicked()
+                    {
+                        self.handle_inspect();
+                    }
+                    ui.checkbox(&mut self.config.audio_only, "仅音频");
+                    egui::ComboBox::new(egui::Id::new("container_select"), "")
+                        .selected_text(self.config.container.name())
+                        .show_ui(ui, |ui| {
+                            for container in Container::ALL {
+                                ui.selectable_value(
+                                    &mut self.config.container,
+                                    container,
+                                    container.name(),
+                                );
+                            }
+                        });
                 });
+                // 账号无法获取所选清晰度时提示
+                let rsl = match self.selected_resolution.as_str() {
+                    "" => "4K",
+                    rsl => rsl,
+                };
+                if let Some(warning) = account
+                    .as_ref()
+                    .filter(|_| !self.config.audio_only)
+                    .and_then(|a| a.quality_warning(rsl))
+                {
+                    ui.colored_label(ui.visuals().warn_fg_color, warning);
+                }
 
                 ui.separator();
 
@@ -235,28 +526,22 @@ impl eframe::App for MyApp {
                         })
                     };
                     self.update_info(info_text.clone());
-                    if self.info.is_empty() {
-                        self.update_pic(false);
-                    } else {
-                        self.update_pic(true);
-                    }
+                    self.update_cover(ui.ctx());
                     ui.vertical(|ui| {
                         ui.label("封面:");
-                        //println!("pic:{}", self.pic);
-                        if self.pic {
-                            let path = Path::new("../pic.png");
-                            if path.exists() {
-                                // let path_str = path.to_str().unwrap().to_string();
-                                // ui.add(
-                                //     egui::Image::new(egui::include_image!("../pic.png"))
-                                //         .max_width(200.0)
-                                //         .rounding(10.0),
-                                // );
-                            } else {
-                                ui.label("no download picture");
+                        match &self.cover {
+                            Some(texture) => {
+                                ui.add(
+                                    egui::Image::new(egui::load::SizedTexture::from_handle(
+                                        texture,
+                                    ))
+                                    .max_width(image_size.x)
+                                    .rounding(10.0),
+                                );
+                            }
+                            None => {
+                                ui.label("no picture");
                             }
-                        } else {
-                            ui.label("no picture");
                         }
                     });
                     ui.vertical(|ui| {
@@ -274,7 +559,14 @@ impl eframe::App for MyApp {
                 ui.separator();
 
                 ui.label("process:");
-                //ui.add(ProgressBar::new(self.progress).show_percentage());
+                let progress = progress::get();
+                if !progress.stage.is_empty() {
+                    ui.label(&progress.stage);
+                    ui.add(ProgressBar::new(progress.fraction()).show_percentage());
+                    if progress.position < progress.total {
+                        ctx.request_repaint_after(std::time::Duration::from_millis(200));
+                    }
+                }
             }
             View::Settings => {
                 let available_size = ui.available_size();
@@ -283,6 +575,23 @@ impl eframe::App for MyApp {
                 }
                 ui.heading("Settings");
 
+                ui.horizontal(|ui| {
+                    ui.label("新账号:");
+                    ui.text_edit_singleline(&mut self.new_profile);
+                    if ui.button("添加并登录").clicked() {
+                        let name = self.new_profile.trim().to_string();
+                        match profile::check_name(&name) {
+                            Ok(_) => {
+                                self.switch_profile(&name);
+                                self.new_profile.clear();
+                                self.current_view = View::MainMenu;
+                                self.login();
+                            }
+                            Err(e) => eprintln!("Error occurred: {}", e),
+                        }
+                    }
+                });
+
                 ui.horizontal(|ui| {
                     ui.label("Save Path:");
                     ui.add_sized(
@@ -290,6 +599,255 @@ impl eframe::App for MyApp {
                         egui::TextEdit::multiline(&mut self.save_path),
                     );
                 });
+
+                ui.horizontal(|ui| {
+                    ui.label("默认格式:");
+                    egui::ComboBox::new(egui::Id::new("default_container"), "")
+                        .selected_text(self.config.container.name())
+                        .show_ui(ui, |ui| {
+                            for container in Container::ALL {
+                                ui.selectable_value(
+                                    &mut self.config.container,
+                                    container,
+                                    container.name(),
+                                );
+                            }
+                        });
+                });
+
+                ui.checkbox(&mut self.config.use_ffmpeg, "mp4也使用ffmpeg合并");
+                ui.add_enabled(
+                    !self.config.use_ffmpeg,
+                    egui::Checkbox::new(&mut self.config.stream_mux, "边下载边合并(不生成m4s)"),
+                );
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.save_cover, "保存封面");
+                    ui.checkbox(&mut self.config.embed_cover, "嵌入封面");
+                    ui.checkbox(&mut self.config.cover_original, "封面原图");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("失败重试次数:");
+                    ui.add(egui::DragValue::new(&mut self.config.retries).range(0..=10));
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.subtitles, "下载字幕");
+                    egui::ComboBox::new(egui::Id::new("subtitle_format"), "")
+                        .selected_text(self.config.subtitle_format.name())
+                        .show_ui(ui, |ui| {
+                            for format in SubtitleFormat::ALL {
+                                ui.selectable_value(
+                                    &mut self.config.subtitle_format,
+                                    format,
+                                    format.name(),
+                                );
+                            }
+                        });
+                    ui.label("语言(逗号分隔，留空为全部):");
+                    let mut langs = self.config.subtitle_langs.join(",");
+                    if ui.text_edit_singleline(&mut langs).changed() {
+                        self.config.subtitle_langs =
+                            langs.split(',').map(|l| l.trim().to_string()).collect();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(
+                        &mut self.config.embed_subtitles,
+                        "嵌入字幕轨(mp4需要ffmpeg)",
+                    );
+                    ui.label("默认字幕:");
+                    ui.text_edit_singleline(&mut self.config.default_subtitle);
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.danmaku, "下载弹幕(转换为ASS)");
+                    ui.add_enabled(
+                        self.config.danmaku,
+                        egui::Checkbox::new(&mut self.config.danmaku_json, "另存JSON"),
+                    );
+                });
+                ui.add_enabled_ui(self.config.danmaku, |ui| {
+                    let profile = &mut self.config.danmaku_profile;
+                    ui.horizontal(|ui| {
+                        ui.label("弹幕类型:");
+                        ui.checkbox(&mut profile.show_scroll, "滚动");
+                        ui.checkbox(&mut profile.show_top, "顶部");
+                        ui.checkbox(&mut profile.show_bottom, "底部");
+                        ui.checkbox(&mut profile.show_advanced, "高级");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("屏蔽关键词(逗号分隔):");
+                        let mut keywords = profile.block_keywords.join(",");
+                        if ui.text_edit_singleline(&mut keywords).changed() {
+                            profile.block_keywords =
+                                keywords.split(',').map(|k| k.trim().to_string()).collect();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("屏蔽正则(每行一个):");
+                        let mut regex = profile.block_regex.join("\n");
+                        if ui.text_edit_multiline(&mut regex).changed() {
+                            profile.block_regex = regex.lines().map(|r| r.to_string()).collect();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("同屏上限(0不限):");
+                        ui.add(egui::DragValue::new(&mut profile.max_on_screen).range(0..=500));
+                        ui.label("滚动时长:");
+                        ui.add(
+                            egui::DragValue::new(&mut profile.scroll_duration)
+                                .range(1.0..=30.0)
+                                .speed(0.1),
+                        );
+                        ui.label("固定时长:");
+                        ui.add(
+                            egui::DragValue::new(&mut profile.fixed_duration)
+                                .range(1.0..=30.0)
+                                .speed(0.1),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("字号:");
+                        ui.add(
+                            egui::DragValue::new(&mut profile.font_scale)
+                                .range(0.3..=3.0)
+                                .speed(0.05),
+                        );
+                        ui.label("不透明度:");
+                        ui.add(egui::Slider::new(&mut profile.opacity, 0.0..=1.0));
+                        ui.label("合并重复(秒，0关闭):");
+                        ui.add(
+                            egui::DragValue::new(&mut profile.repeat_window)
+                                .range(0.0..=60.0)
+                                .speed(0.5),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("导出规则").clicked() {
+                            match profile.save("danmaku_profile.json") {
+                                Ok(_) => println!("danmaku_profile.json saved"),
+                                Err(e) => eprintln!("Error occurred: {}", e),
+                            }
+                        }
+                        if ui.button("导入规则").clicked() {
+                            match DanmakuProfile::load("danmaku_profile.json") {
+                                Ok(loaded) => *profile = loaded,
+                                Err(e) => eprintln!("Error occurred: {}", e),
+                            }
+                        }
+                    });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("压制进画面:");
+                    egui::ComboBox::new(egui::Id::new("burn"), "")
+                        .selected_text(self.config.burn.name())
+                        .show_ui(ui, |ui| {
+                            for burn in BurnSource::ALL {
+                                ui.selectable_value(&mut self.config.burn, burn, burn.name());
+                            }
+                        });
+                    egui::ComboBox::new(egui::Id::new("burn_encoder"), "")
+                        .selected_text(self.config.burn_encoder.name())
+                        .show_ui(ui, |ui| {
+                            for encoder in BurnEncoder::ALL {
+                                ui.selectable_value(
+                                    &mut self.config.burn_encoder,
+                                    encoder,
+                                    encoder.name(),
+                                );
+                            }
+                        });
+                    egui::ComboBox::new(egui::Id::new("burn_quality"), "")
+                        .selected_text(self.config.burn_quality.name())
+                        .show_ui(ui, |ui| {
+                            for quality in BurnQuality::ALL {
+                                ui.selectable_value(
+                                    &mut self.config.burn_quality,
+                                    quality,
+                                    quality.name(),
+                                );
+                            }
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.chapters, "写入看点章节");
+                    ui.checkbox(&mut self.config.export_chapters, "另存章节文本");
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("ffmpeg路径:");
+                    ui.text_edit_singleline(&mut self.config.ffmpeg_path);
+                    if ui.button("检测").clicked() {
+                        self.check_ffmpeg();
+                    }
+                });
+                let ffmpeg_status = {
+                    let status = self.mutex_ffmpeg.clone();
+                    tokio::task::block_in_place(|| {
+                        let lock = futures::executor::block_on(status.lock());
+                        lock.clone()
+                    })
+                };
+                ui.label(ffmpeg_status);
+
+                if ui.button("保存设置").clicked() {
+                    match config::save_config(&self.config) {
+                        Ok(_) => println!("config saved"),
+                        Err(e) => eprintln!("Error occurred: {}", e),
+                    }
+                }
+            }
+            View::Inspect => {
+                if ui.button("Go to Main Menu").clicked() {
+                    self.current_view = View::MainMenu;
+                }
+                ui.heading("格式");
+
+                let media = {
+                    let media = self.mutex_media.clone();
+                    tokio::task::block_in_place(|| {
+                        let lock = futures::executor::block_on(media.lock());
+                        lock.clone()
+                    })
+                };
+                match media {
+                    None => {
+                        ui.label("loading...");
+                    }
+                    Some(info) => {
+                        ui.label(&info.title);
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            egui::Grid::new("video_streams")
+                                .striped(true)
+                                .show(ui, |ui| {
+                                    for v in &info.video {
+                                        ui.label(&v.label);
+                                        ui.label(&v.codecs);
+                                        ui.label(format!("{}x{}", v.width, v.height));
+                                        ui.label(&v.frame_rate);
+                                        ui.label(inspect::format_size(v.size));
+                                        ui.end_row();
+                                    }
+                                    for a in &info.audio {
+                                        ui.label(&a.label);
+                                        ui.label(&a.codecs);
+                                        ui.label("");
+                                        ui.label("");
+                                        ui.label(inspect::format_size(a.size));
+                                        ui.end_row();
+                                    }
+                                });
+                            if info.subtitles.is_empty() {
+                                ui.label("字幕: 无");
+                            } else {
+                                ui.label(format!("字幕: {}", info.subtitles.join(", ")));
+                            }
+                            ui.label(if info.danmaku {
+                                "弹幕: 有"
+                            } else {
+                                "弹幕: 无"
+                            });
+                        });
+                    }
+                }
             }
             View::About => {
                 if ui.button("Go to Main Menu").clicked() {
@@ -300,6 +858,7 @@ impl eframe::App for MyApp {
                 ui.label("这里是关于页面");
             }
         });
+        self.login_window(ctx);
     }
 }
 
@@ -322,11 +881,47 @@ fn load_fonts(ctx: &egui::Context) {
     ctx.set_fonts(fonts);
 }
 
-async fn login() {
-    let client: Client = reqwest::Client::new();
-    if login_qrcode(&client).await {
-        println!("Login successful");
-    } else {
-        println!("Login failed");
+/// 申请二维码并轮询登录状态，每次轮询的结果写入status
+///
+/// 返回最终状态：登录成功的账号，或失效的二维码以便重新获取
+async fn login(status: &Mutex<LoginStatus>) -> Result<LoginStatus> {
+    // 登录响应的Set-Cookie保存在jar中
+    let jar = Arc::new(Jar::default());
+    let client = refresh_cookie::client_with_jar(jar.clone())?;
+    let qrcode = qrcode_login::generate_qrcode(&client).await?;
+    let waiting = |state| LoginStatus::Waiting {
+        url: qrcode.url.clone(),
+        state,
+    };
+    *status.lock().await = waiting(PollState::NotScanned);
+    // 3分钟超时
+    for _ in 0..60 {
+        let state = qrcode_login::poll_qrcode(&client, &qrcode.qrcode_key).await?;
+        match state {
+            PollState::Success { url, refresh_token } => {
+                let cookies =
+                    qrcode_login::save_cookie(&client, &jar, &url, &refresh_token).await?;
+                println!("Login successful");
+                let account = match account::fetch_account(&client).await {
+                    Ok(account) => account,
+                    Err(e) => {
+                        // 查询失败时只显示uid
+                        eprintln!("Error occurred: {}", e);
+                        let mid = cookies.extra.get("DedeUserID").cloned().unwrap_or_default();
+                        Account {
+                            is_login: true,
+                            mid: mid.parse().unwrap_or(0),
+                            uname: mid,
+                            ..Default::default()
+                        }
+                    }
+                };
+                return Ok(LoginStatus::Success(account));
+            }
+            PollState::Expired => return Ok(waiting(PollState::Expired)),
+            state => *status.lock().await = waiting(state),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
     }
+    Ok(waiting(PollState::Expired))
 }
@@ -0,0 +1,104 @@
+use crate::chapter::Chapter;
+use crate::subtitle::SubtitleTrack;
+use chrono::{TimeZone, Utc};
+
+/// 写入输出文件的元数据
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub title: String,
+    /// UP主/出品方
+    pub artist: String,
+    /// 发布日期 YYYY-MM-DD
+    pub date: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// BV号或ep号
+    pub id: String,
+    /// 来源地址
+    pub url: String,
+    /// 番剧名，普通视频为空
+    pub series: String,
+    /// 第几季/第几集，0表示没有
+    pub season: u32,
+    pub episode: u32,
+    /// 封面地址
+    pub cover: String,
+    /// 看点章节
+    pub chapters: Vec<Chapter>,
+    /// 时长(秒)，用于显示合并进度，不写入文件
+    pub duration: u64,
+    /// 弹幕和字幕使用的cid
+    pub cid: String,
+    /// 下载的视频分辨率，用于弹幕排版
+    pub width: u32,
+    pub height: u32,
+    /// 可用的字幕，另存为字幕文件
+    pub subtitles: Vec<SubtitleTrack>,
+}
+
+impl Metadata {
+    /// 文本标签，(ffmpeg中的名称, 值)
+    pub fn text_tags(&self) -> Vec<(&'static str, String)> {
+        let tags = [
+            ("title", self.title.clone()),
+            ("artist", self.artist.clone()),
+            ("date", self.date.clone()),
+            ("description", self.description.clone()),
+            ("comment", self.url.clone()),
+            ("keywords", self.tags.join(",")),
+            ("episode_id", self.id.clone()),
+            ("show", self.series.clone()),
+            ("album", self.series.clone()),
+        ];
+        tags.into_iter().filter(|(_, v)| !v.is_empty()).collect()
+    }
+
+    /// 转换为ffmpeg的-metadata参数
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        let mut tags = self.text_tags();
+        if self.season > 0 {
+            tags.push(("season_number", self.season.to_string()));
+        }
+        if self.episode > 0 {
+            tags.push(("episode_sort", self.episode.to_string()));
+        }
+        tags.iter()
+            .flat_map(|(k, v)| ["-metadata".to_string(), format!("{}={}", k, v)])
+            .collect()
+    }
+}
+
+/// 将秒级时间戳转换为日期 +8
+pub fn format_date(timestamp: i64) -> String {
+    if timestamp <= 0 {
+        return String::new();
+    }
+    Utc.timestamp_opt(timestamp + 8 * 3600, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_ffmpeg_args() {
+    let metadata = Metadata {
+        title: "标题".to_string(),
+        date: format_date(1700000000),
+        tags: vec!["a".to_string(), "b".to_string()],
+        episode: 3,
+        ..Default::default()
+    };
+    assert_eq!(
+        metadata.ffmpeg_args(),
+        vec![
+            "-metadata",
+            "title=标题",
+            "-metadata",
+            "date=2023-11-15",
+            "-metadata",
+            "keywords=a,b",
+            "-metadata",
+            "episode_sort=3"
+        ]
+    );
+}
@@ -0,0 +1,23 @@
+/// 为只有单元变体的枚举生成 ALL、name() 和 from_name()
+///
+/// 名称用于命令行选项和界面显示，from_name不区分大小写
+macro_rules! named_enum {
+    ($ty:ident { $($variant:ident => $name:literal),+ $(,)? }) => {
+        impl $ty {
+            pub const ALL: [$ty; [$($name),+].len()] = [$($ty::$variant),+];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($ty::$variant => $name,)+
+                }
+            }
+
+            pub fn from_name(s: &str) -> Option<$ty> {
+                $ty::ALL
+                    .iter()
+                    .find(|v| v.name().eq_ignore_ascii_case(s))
+                    .copied()
+            }
+        }
+    };
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// 默认账号，cookie保存在工作目录的load中
+pub const DEFAULT: &str = "default";
+/// 其他账号的cookie目录
+const PROFILE_DIR: &str = "./profiles";
+
+/// 当前使用的账号，所有请求从它的cookie文件读取
+static ACTIVE: Mutex<String> = Mutex::new(String::new());
+
+/// 切换当前账号，为空时使用默认账号
+pub fn set_active(name: &str) {
+    *ACTIVE.lock().unwrap() = name.trim().to_string();
+}
+
+pub fn active() -> String {
+    let name = ACTIVE.lock().unwrap().clone();
+    if name.is_empty() {
+        DEFAULT.to_string()
+    } else {
+        name
+    }
+}
+
+/// 账号的cookie文件，默认账号沿用load
+pub fn path_of(name: &str) -> PathBuf {
+    let name = name.trim();
+    if name.is_empty() || name == DEFAULT {
+        PathBuf::from("./load")
+    } else {
+        Path::new(PROFILE_DIR).join(format!("{}.json", name))
+    }
+}
+
+/// 当前账号的cookie文件
+pub fn cookie_path() -> PathBuf {
+    path_of(&active())
+}
+
+/// 账号名用作文件名，只允许字母、数字、-和_
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Invalid profile name {:?}: only letters, digits, - and _ are allowed",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// 已登录的账号，默认账号总在第一个
+pub fn list() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(PROFILE_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    if path.extension()? != "json" {
+                        return None;
+                    }
+                    let name = path.file_stem()?.to_str()?.to_string();
+                    (name != DEFAULT && check_name(&name).is_ok()).then_some(name)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names.insert(0, DEFAULT.to_string());
+    names
+}
+
+/// 删除账号的cookie，删除当前账号时切换回默认账号
+pub fn remove(name: &str) -> Result<()> {
+    check_name(name)?;
+    let path = path_of(name);
+    std::fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove {:?}: {}", path, e))?;
+    if active() == name {
+        set_active(DEFAULT);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_profile() {
+    assert_eq!(path_of(""), PathBuf::from("./load"));
+    assert_eq!(path_of(DEFAULT), PathBuf::from("./load"));
+    assert_eq!(path_of("vip"), Path::new("./profiles").join("vip.json"));
+    assert!(check_name("港澳台_1").is_ok());
+    assert!(check_name("../load").is_err());
+    assert!(check_name("a b").is_err());
+    assert!(check_name("").is_err());
+}
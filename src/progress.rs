@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+
+/// 当前任务的进度，下载和合并时更新，供界面显示
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// 当前阶段，如"下载 xxx_video.m4s"、"合并 xxx"
+    pub stage: String,
+    pub position: u64,
+    /// 总量，0表示未知
+    pub total: u64,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        (self.position as f64 / self.total as f64).min(1.0) as f32
+    }
+}
+
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress {
+    stage: String::new(),
+    position: 0,
+    total: 0,
+});
+
+/// 开始新的阶段
+pub fn start(stage: &str, total: u64) {
+    let mut progress = PROGRESS.lock().unwrap();
+    progress.stage = stage.to_string();
+    progress.position = 0;
+    progress.total = total;
+}
+
+/// 总量在开始后才知道时追加，如同时下载多个流
+pub fn add_total(total: u64) {
+    PROGRESS.lock().unwrap().total += total;
+}
+
+pub fn set_position(position: u64) {
+    PROGRESS.lock().unwrap().position = position;
+}
+
+pub fn inc(delta: u64) {
+    PROGRESS.lock().unwrap().position += delta;
+}
+
+/// 当前阶段完成
+pub fn finish() {
+    let mut progress = PROGRESS.lock().unwrap();
+    progress.position = progress.total;
+}
+
+pub fn get() -> Progress {
+    PROGRESS.lock().unwrap().clone()
+}
@@ -1,14 +1,17 @@
-use anyhow::Result;
-use qrcode::render::svg;
+use anyhow::{anyhow, Result};
+use qrcode::render::{svg, unicode};
 use qrcode::QrCode;
+use reqwest::cookie::Jar;
 use reqwest::{header::HeaderValue, Client};
 use resvg::tiny_skia::Pixmap;
 use resvg::usvg::{Options, Transform, Tree};
+use serde::Deserialize;
 use serde_json::Value;
-use std::collections::HashMap;
 use std::io::Write;
 use std::process::Command;
-use urlencoding::encode;
+
+use crate::profile;
+use crate::refresh_cookie::{cookie_url, Cookies, NOT_COOKIES};
 
 /// 渲染SVG到PNG
 fn render_svg_to_png(svg_data: &str, output_path: &str) -> Result<()> {
@@ -33,18 +36,140 @@ fn render_svg_to_png(svg_data: &str, output_path: &str) -> Result<()> {
     Ok(())
 }
 
-/// 获取二维码接口数据
-async fn apply_qrcode(client: &Client) -> Result<String, reqwest::Error> {
+/// 接口的外层结构
+#[derive(Deserialize, Debug)]
+struct ApiResponse<T> {
+    code: i64,
+    #[serde(default)]
+    message: String,
+    data: Option<T>,
+}
+
+/// 申请到的二维码
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct QrcodeInfo {
+    /// 二维码内容
+    pub url: String,
+    /// 轮询登录状态使用
+    pub qrcode_key: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct PollData {
+    url: String,
+    refresh_token: String,
+    code: i64,
+    message: String,
+}
+
+/// 二维码的登录状态
+#[derive(Debug, Clone, PartialEq)]
+pub enum PollState {
+    /// 86101 未扫码
+    NotScanned,
+    /// 86090 已扫码，等待手机上确认
+    Scanned,
+    /// 86038 二维码已失效
+    Expired,
+    /// 0 登录成功，url为跳转地址
+    Success { url: String, refresh_token: String },
+    /// 其他状态
+    Unknown { code: i64, message: String },
+}
+
+impl PollState {
+    /// 显示给用户的状态
+    pub fn describe(&self) -> String {
+        match self {
+            PollState::NotScanned => "等待扫码".to_string(),
+            PollState::Scanned => "已扫码，请在手机上确认".to_string(),
+            PollState::Expired => "二维码已失效".to_string(),
+            PollState::Success { .. } => "登录成功".to_string(),
+            PollState::Unknown { code, message } => format!("{}: {}", code, message),
+        }
+    }
+}
+
+/// 二维码的显示方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QrcodeDisplay {
+    /// 保存为output.png，Windows下自动打开
+    Png,
+    /// 用Unicode半块字符打印在终端中，用于SSH等无图形界面的环境
+    Terminal,
+}
+
+/// 解析申请二维码接口的返回
+fn parse_generate(response: &str) -> Result<QrcodeInfo> {
+    let response: ApiResponse<QrcodeInfo> = serde_json::from_str(response)?;
+    if response.code != 0 {
+        return Err(anyhow!(
+            "qrcode/generate failed with code {}: {}",
+            response.code,
+            response.message
+        ));
+    }
+    response
+        .data
+        .filter(|d| !d.url.is_empty() && !d.qrcode_key.is_empty())
+        .ok_or_else(|| anyhow!("qrcode/generate returned no qrcode"))
+}
+
+/// 解析轮询接口的返回，外层code不为0时(如被风控)返回错误
+fn parse_poll(response: &str) -> Result<PollState> {
+    let response: ApiResponse<PollData> = serde_json::from_str(response)?;
+    if response.code != 0 {
+        return Err(anyhow!(
+            "qrcode/poll failed with code {}: {}",
+            response.code,
+            response.message
+        ));
+    }
+    let data = response
+        .data
+        .ok_or_else(|| anyhow!("qrcode/poll returned no data"))?;
+    Ok(match data.code {
+        0 => PollState::Success {
+            url: data.url,
+            refresh_token: data.refresh_token,
+        },
+        86101 => PollState::NotScanned,
+        86090 => PollState::Scanned,
+        86038 => PollState::Expired,
+        code => PollState::Unknown {
+            code,
+            message: data.message,
+        },
+    })
+}
+
+/// 申请登录二维码
+pub async fn generate_qrcode(client: &Client) -> Result<QrcodeInfo> {
     let url = "https://passport.bilibili.com/x/passport-login/web/qrcode/generate";
     let resp = client.get(url).send().await?.text().await?;
-    Ok(resp)
+    parse_generate(&resp)
 }
 
-/// 解析二维码接口数据
-fn get_url_and_key(response: &str) -> (&str, &str) {
-    let url = &response[47..193]; //切片
-    let key = &response[209..241];
-    return (url, key);
+/// 查询一次二维码的登录状态，登录成功时cookie写入客户端的jar
+pub async fn poll_qrcode(client: &Client, qrcode_key: &str) -> Result<PollState> {
+    let url = "https://passport.bilibili.com/x/passport-login/web/qrcode/poll";
+    let value = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+    let mut headers: reqwest::header::HeaderMap = reqwest::header::HeaderMap::new();
+    headers.insert("User-Agent", HeaderValue::from_static(value));
+    headers.insert(
+        "Referer",
+        HeaderValue::from_static("https://passport.bilibili.com"),
+    );
+    let resp: String = client
+        .get(url)
+        .headers(headers)
+        .query(&[("qrcode_key", qrcode_key)])
+        .send()
+        .await?
+        .text()
+        .await?;
+    parse_poll(&resp)
 }
 
 /// 显示二维码
@@ -59,143 +184,236 @@ fn show_qrcode(url: &str) -> Result<()> {
     Ok(())
 }
 
-/// 轮询二维码登录状态
-async fn qrcode_pull(client: &Client, qrcode_key: &str) -> Result<bool, reqwest::Error> {
-    let mut flag: bool = false;
-    let url = "https://passport.bilibili.com/x/passport-login/web/qrcode/poll";
-    let value = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
-    let mut headers: reqwest::header::HeaderMap = reqwest::header::HeaderMap::new();
-    headers.insert("User-Agent", HeaderValue::from_static(value));
-    headers.insert(
-        "Referer",
-        HeaderValue::from_static("https://passport.bilibili.com"),
-    );
-    let mut params: HashMap<&str, &str> = HashMap::new();
-    params.insert("qrcode_key", qrcode_key);
-    let cookie: Option<String>;
+/// 将二维码渲染为终端字符，每个字符显示上下两个模块
+///
+/// 终端多为深色背景，使用亮色绘制深色模块，保留静区以便识别
+fn render_terminal(url: &str) -> Result<String> {
+    let code = QrCode::new(url.as_bytes())?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+/// 将二维码渲染为灰度像素，每个模块scale像素，保留4个模块的静区
+///
+/// 返回边长和逐行的像素，用于在图形界面中生成纹理
+pub fn render_gray(url: &str, scale: usize) -> Result<(usize, Vec<u8>)> {
+    let code = QrCode::new(url.as_bytes())?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let side = (width + 8) * scale;
+    let mut pixels = vec![255u8; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+        let (x, y) = ((i % width + 4) * scale, (i / width + 4) * scale);
+        for row in y..y + scale {
+            pixels[row * side + x..row * side + x + scale].fill(0);
+        }
+    }
+    Ok((side, pixels))
+}
+
+/// 轮询二维码登录状态，登录成功后保存cookie
+///
+/// 终端模式下在同一行刷新状态和剩余时间，否则状态变化时输出一行
+async fn qrcode_pull(
+    client: &Client,
+    jar: &Jar,
+    qrcode_key: &str,
+    display: QrcodeDisplay,
+) -> Result<bool> {
+    let mut last_state = None;
     let mut count = 0;
-    loop {
-        let resp: String = client
-            .get(url)
-            .headers(headers.clone())
-            .query(&params)
-            .send()
-            .await?
-            .text()
-            .await?;
-        //println!("{}", resp);
-        let (code1, url, refresh_token, code2, message) = wait_for_login(resp);
-        if code1 == 0 {
-            if code2 == 0 {
-                //登录成功
-                std::fs::remove_file("output.png").unwrap();
-                let result = format!("{}&refresh_token={}", url, refresh_token);
-                cookie = Some(result);
-                flag = true;
-                break;
-            } else {
-                println!("Code: {}, Message: {}", code2, message);
+    let result = loop {
+        let state = match poll_qrcode(client, qrcode_key).await {
+            Err(e) => {
+                // 外层code不为0时一般是ip被风控
+                eprintln!("Error occurred: {}", e);
+                break None;
             }
-        } else {
-            println!("ip is baned, please change ip");
-            cookie = None;
-            break;
+            Ok(state) => state,
+        };
+        if display == QrcodeDisplay::Terminal {
+            print!("\r\x1b[2K{} (剩余{}s)", state.describe(), 180 - count);
+            std::io::stdout().flush()?;
+        } else if last_state.as_ref() != Some(&state) {
+            // 状态变化时才输出
+            println!("{}", state.describe());
         }
-        std::thread::sleep(std::time::Duration::from_secs_f64(3.0));
+        match &state {
+            PollState::Success { url, refresh_token } => {
+                break Some((url.clone(), refresh_token.clone()))
+            }
+            PollState::Expired => break None,
+            _ => {}
+        }
+        last_state = Some(state);
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
         count += 3;
         if count >= 180 {
             // 3分钟超时
-            std::fs::remove_file("output.png").unwrap();
             println!("Timeout");
-            cookie = None;
-            break;
+            break None;
         }
+    };
+    if display == QrcodeDisplay::Terminal {
+        println!();
     }
-    if let Some(cookie) = &cookie {
-        match save_cookie(cookie.to_string()) {
-            Ok(_) => {
-                println!("Cookie saved successfully");
-                flag = true;
+    let _ = std::fs::remove_file("output.png");
+    let Some((url, refresh_token)) = result else {
+        return Ok(false);
+    };
+    save_cookie(client, jar, &url, &refresh_token).await?;
+    println!("Cookie saved successfully");
+    Ok(true)
+}
+
+/// 保存登录响应写入jar的cookie和refresh_token
+///
+/// jar中没有SESSDATA时使用跳转地址中的参数；同时获取buvid3/buvid4，部分接口的风控需要
+pub async fn save_cookie(
+    client: &Client,
+    jar: &Jar,
+    url: &str,
+    refresh_token: &str,
+) -> Result<Cookies> {
+    if Cookies::from_jar(jar, refresh_token).SESSDATA.is_empty() {
+        let query = url.split_once('?').map(|(_, q)| q).unwrap_or("");
+        for param in query.split('&') {
+            if let Some((key, value)) = param.split_once('=') {
+                if !NOT_COOKIES.contains(&key) {
+                    jar.add_cookie_str(
+                        &format!("{}={}; Domain=.bilibili.com; Path=/", key, value),
+                        &cookie_url(),
+                    );
+                }
             }
-            Err(e) => eprintln!("Error occurred: {}", e),
-        };
-    } else {
-        eprintln!("Error: Cookie is not initialized");
+        }
     }
-    Ok(flag)
-}
-
-/// 保存cookie到文件
-fn save_cookie(cookie: String) -> Result<bool> {
-    let parts: Vec<&str> = cookie.split('?').collect();
-    let params: Vec<&str> = parts[1].split('&').collect();
-    let mut map: std::collections::HashMap<&str, String> = std::collections::HashMap::new();
-    for param in params.iter() {
-        let kv: Vec<&str> = param.split('=').collect();
-        let key = kv[0];
-        let value = encode(kv[1]).into_owned().to_string(); // url编码
-        map.insert(key, value);
+    if let Err(e) = fetch_buvid(client, jar).await {
+        eprintln!("Failed to get buvid: {}", e);
     }
-    let mut file = std::fs::File::create("load")?;
-    let serialized_map = serde_json::to_string(&map)?;
-    file.write_all(serialized_map.as_bytes())?;
-    Ok(true)
+    let cookies = Cookies::from_jar(jar, refresh_token);
+    cookies.save(&profile::cookie_path())?;
+    Ok(cookies)
 }
 
-fn wait_for_login(response: String) -> (i32, String, String, i32, String) {
-    let parsed: Value = serde_json::from_str(&response).unwrap();
-    let code1 = parsed["code"].as_i64().unwrap() as i32;
-    let code2 = parsed["data"]["code"].as_i64().unwrap() as i32;
-    let url = parsed["data"]["url"].as_str().unwrap();
-    let refresh_token = parsed["data"]["refresh_token"].as_str().unwrap();
-    let message = parsed["data"]["message"].as_str().unwrap();
-
-    return (
-        code1,
-        url.to_string(),
-        refresh_token.to_string(),
-        code2,
-        message.to_string(),
-    );
+/// 获取buvid3/buvid4并写入jar
+async fn fetch_buvid(client: &Client, jar: &Jar) -> Result<()> {
+    let url = "https://api.bilibili.com/x/frontend/finger/spi";
+    let json: Value = client.get(url).send().await?.json().await?;
+    for (name, key) in [("buvid3", "b_3"), ("buvid4", "b_4")] {
+        if let Some(value) = json["data"][key].as_str().filter(|v| !v.is_empty()) {
+            jar.add_cookie_str(
+                &format!("{}={}; Domain=.bilibili.com; Path=/", name, value),
+                &cookie_url(),
+            );
+        }
+    }
+    Ok(())
 }
 
 /// 登录二维码接口逻辑
-pub async fn login_qrcode(client: &Client) -> bool {
-    let qrcode_key: Option<String>;
-    let response: Option<String> = match apply_qrcode(&client).await {
-        Ok(response) => Some(response),
+pub async fn login_qrcode(client: &Client, jar: &Jar, display: QrcodeDisplay) -> bool {
+    let qrcode = match generate_qrcode(client).await {
+        Ok(qrcode) => qrcode,
         Err(e) => {
             eprintln!("Error occurred: {}", e);
-            None
+            return false;
         }
     };
-    if let Some(response) = response {
-        let (url, key) = get_url_and_key(&response);
-        let decoded_url = url.replace(r"\u0026", "&");
-        //println!("QR Code URL: {}", decoded_url);
-        //println!("QR Code key: {}", key);
-        qrcode_key = Some(key.to_string().clone());
-        match show_qrcode(&decoded_url) {
-            Err(e) => eprintln!("Error occurred: {}", e),
-            Ok(_) => println!("QR Code displayed successfully"),
-        };
-    } else {
-        println!("Error occurred: {}", "No response");
-        return false;
+    let shown = match display {
+        QrcodeDisplay::Png => show_qrcode(&qrcode.url),
+        QrcodeDisplay::Terminal => render_terminal(&qrcode.url).map(|code| {
+            println!("{}", code);
+            println!("请使用哔哩哔哩手机客户端扫码登录");
+        }),
+    };
+    match shown {
+        Err(e) => eprintln!("Error occurred: {}", e),
+        Ok(_) => println!("QR Code displayed successfully"),
+    };
+    match qrcode_pull(client, jar, &qrcode.qrcode_key, display).await {
+        Ok(flag) => flag,
+        Err(e) => {
+            eprintln!("Error occurred: {}", e);
+            false
+        }
     }
+}
 
-    if let Some(qrcode_key) = qrcode_key {
-        match qrcode_pull(&client, &qrcode_key).await {
-            Ok(flag) => {
-                return flag;
-            }
-            Err(e) => {
-                eprintln!("Error occurred: {}", e);
-                return false;
-            }
+#[test]
+fn test_parse_generate() {
+    let response = r#"{"code":0,"message":"0","ttl":1,"data":{"url":"https://account.bilibili.com/h5/account-h5/auth/scan-web?navhide=1\u0026callback=close\u0026qrcode_key=8a9c8d0f1e2b3c4d5e6f708192a3b4c5\u0026from=","qrcode_key":"8a9c8d0f1e2b3c4d5e6f708192a3b4c5"}}"#;
+    let qrcode = parse_generate(response).unwrap();
+    assert_eq!(qrcode.qrcode_key, "8a9c8d0f1e2b3c4d5e6f708192a3b4c5");
+    assert!(qrcode.url.contains("&callback=close&"));
+    assert!(parse_generate(r#"{"code":-412,"message":"请求被拦截"}"#).is_err());
+    assert!(parse_generate(r#"{"code":0,"data":{"url":"","qrcode_key":""}}"#).is_err());
+    assert!(parse_generate("<html>").is_err());
+}
+
+#[test]
+fn test_parse_poll() {
+    let poll = |code: i64, message: &str| {
+        format!(
+            r#"{{"code":0,"message":"0","ttl":1,"data":{{"url":"","refresh_token":"","timestamp":0,"code":{},"message":"{}"}}}}"#,
+            code, message
+        )
+    };
+    assert_eq!(
+        parse_poll(&poll(86101, "未扫码")).unwrap(),
+        PollState::NotScanned
+    );
+    assert_eq!(
+        parse_poll(&poll(86090, "二维码已扫码未确认")).unwrap(),
+        PollState::Scanned
+    );
+    assert_eq!(
+        parse_poll(&poll(86038, "二维码已失效")).unwrap(),
+        PollState::Expired
+    );
+    assert_eq!(
+        parse_poll(&poll(1, "其他")).unwrap(),
+        PollState::Unknown {
+            code: 1,
+            message: "其他".to_string()
         }
-    } else {
-        eprintln!("Error: QR Code key is not initialized");
-        return false;
-    }
+    );
+    let success = r#"{"code":0,"message":"0","ttl":1,"data":{"url":"https://passport.biligame.com/x/passport-login/web/crossDomain?DedeUserID=1\u0026SESSDATA=a%2Cb\u0026bili_jct=c\u0026gourl=https%3A%2F%2Fwww.bilibili.com","refresh_token":"d1e2f3","timestamp":1700000000000,"code":0,"message":""}}"#;
+    let PollState::Success { url, refresh_token } = parse_poll(success).unwrap() else {
+        panic!("expected success");
+    };
+    assert!(url.contains("SESSDATA=a%2Cb&bili_jct=c"));
+    assert_eq!(refresh_token, "d1e2f3");
+    assert!(parse_poll(r#"{"code":-412,"message":"请求被拦截"}"#).is_err());
+}
+
+#[test]
+fn test_render_terminal() {
+    let rendered =
+        render_terminal("https://account.bilibili.com/h5/account-h5/auth/scan-web").unwrap();
+    let lines: Vec<&str> = rendered.lines().collect();
+    let width = lines[0].chars().count();
+    // 每行字符数相同，两个模块一行
+    assert!(lines.iter().all(|l| l.chars().count() == width));
+    assert_eq!(lines.len(), width.div_ceil(2));
+    // 静区使用亮色
+    assert!(lines[0].chars().all(|c| c == '█'));
+    assert_eq!(PollState::Scanned.describe(), "已扫码，请在手机上确认");
+
+    let url = "https://www.bilibili.com";
+    let (side, pixels) = render_gray(url, 2).unwrap();
+    // 加上两侧静区
+    let width = QrCode::new(url.as_bytes()).unwrap().width();
+    assert_eq!(side, (width + 8) * 2);
+    assert_eq!(pixels.len(), side * side);
+    assert!(pixels[..side * 8].iter().all(|p| *p == 255));
+    // 左上角定位图案
+    assert_eq!(pixels[8 * side + 8], 0);
 }
@@ -1,90 +1,168 @@
-use anyhow::{Ok, Result};
-use chrono::{DateTime, TimeZone, Utc};
-use hex;
+use anyhow::{anyhow, Context, Result};
+use reqwest::cookie::{CookieStore, Jar};
 use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::Client;
+use reqwest::{Client, Url};
 use rsa::RsaPublicKey;
 use rsa::{pkcs8::DecodePublicKey, Oaep};
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 use sha2::Sha256;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::process::Command;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
 
-#[derive(Serialize, Deserialize)]
+/// 保存在load文件中的登录状态
+#[derive(Serialize, Deserialize, Debug, Default)]
 #[allow(non_snake_case)]
-#[derive(Debug)]
+#[serde(default)]
 pub struct Cookies {
     pub SESSDATA: String,
     pub bili_jct: String,
+    /// 刷新cookie使用，不是cookie
     pub refresh_token: String,
+    /// 其他cookie，如 DedeUserID、buvid3
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, String>,
 }
 
-// /// 刷新cookie接口逻辑
-// pub async fn refresh_cookie(client: &Client) -> Result<bool> {
-//     let path = Path::new("load");
-//     let cookie = read_cookie(path);
-//     let (code, refresh, timestamp) =
-//         is_need_refresh(client, &cookie)
-//             .await
-//             .unwrap_or((-1, true, String::new()));
-//     let date_time = convert_timestamp_to_date(timestamp.parse::<i64>().unwrap_or(0));
-//     println!(
-//         "code: {}, refresh: {}, timestamp: {}",
-//         code, refresh, date_time
-//     );
-//     if code != 0 {
-//         return Ok(false);
-//     }
-//     //let encrypted_hex = correspond_path(&timestamp).unwrap_or("".to_string());
-//     // match correspond_path(&timestamp) {
-//     //     Result::Ok(encrypted_hex) => {
-//     //         println!("encrypted_hex: {}", encrypted_hex);
-//     //         // let mut input = String::new();
-//     //         // io::stdin()
-//     //         //     .read_line(&mut input)
-//     //         //     .expect("Failed to read line");
-//     //         get_refresh_csrf(&encrypted_hex, client, &cookie).await?;
-//     //     }
-//     //     Err(e) => eprintln!("Error occurred: {}", e),
-//     // }
-
-//     Ok(!refresh)
-// }
-
-// /// 将时间戳转换为日期时间 +8
-// fn convert_timestamp_to_date(timestamp: i64) -> DateTime<Utc> {
-//     let timestamp_in_seconds = timestamp / 1000 + 8 * 3600;
-//     Utc.timestamp_opt(timestamp_in_seconds, (timestamp % 1000) as u32 * 1_000_000)
-//         .single()
-//         .expect("Invalid timestamp")
-// }
-
-/// 读取cookie文件
-pub fn read_cookie(path: &Path) -> Cookies {
+/// 登录跳转地址和旧版load文件中的参数，不是cookie
+pub(crate) const NOT_COOKIES: [&str; 3] = ["gourl", "Expires", "first_domain"];
+
+/// cookie对bilibili.com的全部子域名有效
+pub(crate) fn cookie_url() -> Url {
+    Url::parse("https://www.bilibili.com").unwrap()
+}
+
+impl Cookies {
+    /// 全部cookie的(名称, 值)
+    pub fn pairs(&self) -> Vec<(&str, &str)> {
+        let mut pairs = vec![
+            ("SESSDATA", self.SESSDATA.as_str()),
+            ("bili_jct", self.bili_jct.as_str()),
+        ];
+        pairs.extend(
+            self.extra
+                .iter()
+                .filter(|(name, _)| !NOT_COOKIES.contains(&name.as_str()))
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        pairs.retain(|(_, value)| !value.is_empty());
+        pairs
+    }
+
+    /// 创建包含全部cookie的jar
+    pub fn to_jar(&self) -> Arc<Jar> {
+        let jar = Jar::default();
+        let url = cookie_url();
+        for (name, value) in self.pairs() {
+            jar.add_cookie_str(
+                &format!("{}={}; Domain=.bilibili.com; Path=/", name, value),
+                &url,
+            );
+        }
+        Arc::new(jar)
+    }
+
+    /// 从jar中读取cookie，refresh_token不在cookie中，需要单独传入
+    pub fn from_jar(jar: &Jar, refresh_token: &str) -> Cookies {
+        let mut cookies = Cookies {
+            refresh_token: refresh_token.to_string(),
+            ..Default::default()
+        };
+        let header = jar.cookies(&cookie_url());
+        let header = header.as_ref().and_then(|h| h.to_str().ok()).unwrap_or("");
+        for pair in header.split(';') {
+            let Some((name, value)) = pair.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.to_string();
+            match name {
+                "SESSDATA" => cookies.SESSDATA = value,
+                "bili_jct" => cookies.bili_jct = value,
+                _ => {
+                    cookies.extra.insert(name.to_string(), value);
+                }
+            }
+        }
+        cookies
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// 使用jar保存和发送cookie的客户端，响应中的Set-Cookie会写回jar
+pub fn client_with_jar(jar: Arc<Jar>) -> Result<Client> {
+    Ok(Client::builder().cookie_provider(jar).build()?)
+}
+
+/// 发送全部cookie的客户端
+pub fn create_client(cookie: &Cookies) -> Result<Client> {
+    client_with_jar(cookie.to_jar())
+}
+
+/// 生成correspondPath的公钥
+const REFRESH_PUBKEY: &str = r#"-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDLgd2OAkcGVtoE3ThUREbio0Eg
+Uc/prcajMKXvkCKFCWhJYJcLkcM2DKKcSeFpD/j6Boy538YXnR6VhcuUJOhH2x71
+nzPjfdTcqMz7djHum0qSZA0AyCBDABUqCrfNgCiJ00Ra7GmRj+YCK1NJEuewlb40
+JNrRuoEUXpabUzGB8QIDAQAB
+-----END PUBLIC KEY-----"#;
+
+/// 需要时刷新cookie并保存，返回是否刷新了
+///
+/// 流程：检查是否需要刷新 → 加密correspondPath → 获取refresh_csrf → 刷新 → 确认刷新
+pub async fn refresh_cookie(path: &Path) -> Result<bool> {
+    let cookie = read_cookie_or_not(path).await?;
+    if cookie.SESSDATA.is_empty() || cookie.refresh_token.is_empty() {
+        return Ok(false);
+    }
+    let jar = cookie.to_jar();
+    let client = client_with_jar(jar.clone())?;
+    let (refresh, timestamp) = is_need_refresh(&client, &cookie).await?;
+    if !refresh {
+        return Ok(false);
+    }
+    println!("cookie needs refresh");
+    let correspond_path = correspond_path(timestamp)?;
+    let refresh_csrf = get_refresh_csrf(&client, &correspond_path).await?;
+    // 刷新响应的Set-Cookie会更新jar
+    let refresh_token = refresh_request(&client, &cookie, &refresh_csrf).await?;
+    let new_cookie = Cookies::from_jar(&jar, &refresh_token);
+    if new_cookie.SESSDATA.is_empty() || new_cookie.SESSDATA == cookie.SESSDATA {
+        return Err(anyhow!("Refresh response does not contain new SESSDATA"));
+    }
+    // 确认后旧的refresh_token失效，先保存新的cookie
+    new_cookie.save(path)?;
+    confirm_refresh(&client, &new_cookie.bili_jct, &cookie.refresh_token).await?;
+    println!("cookie refreshed");
+    Ok(true)
+}
+
+/// 读取cookie文件，不存在时为空的cookie
+pub async fn read_cookie_or_not(path: &Path) -> Result<Cookies> {
     if path.exists() {
         //println!("{:?} exists", path);
-        let mut file = File::open(path).unwrap();
+        let mut file = tokio::fs::File::open(path).await?;
         let mut content = String::new();
-        file.read_to_string(&mut content).unwrap();
-        let cookie: Cookies = serde_json::from_str(&content).unwrap();
-        return cookie;
+        file.read_to_string(&mut content).await?;
+        let cookie: Cookies = serde_json::from_str(&content)?;
+        return Ok(cookie);
     } else {
         println!("{:?} does not exist", path);
     }
-    return Cookies {
-        SESSDATA: String::new(),
-        bili_jct: String::new(),
-        refresh_token: String::new(),
-    };
+    Ok(Cookies::default())
 }
 
-/// 创建请求头
-pub fn create_headers(cookie: &Cookies) -> HeaderMap {
+/// 创建请求头，cookie由客户端的jar发送
+pub fn create_headers() -> HeaderMap {
     let value = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
     let mut headers: HeaderMap = HeaderMap::new();
     headers.insert("User-Agent", HeaderValue::from_static(value));
@@ -92,92 +170,168 @@ pub fn create_headers(cookie: &Cookies) -> HeaderMap {
         "Referer",
         HeaderValue::from_static("https://www.bilibili.com"),
     );
-    headers.insert(
-        "Cookie",
-        HeaderValue::from_str(format!("SESSDATA={}", cookie.SESSDATA).as_str()).unwrap(),
-    );
     return headers;
 }
 
-// /// 判断是否需要刷新cookie
-// async fn is_need_refresh(
-//     client: &Client,
-//     cookie: &Cookies,
-// ) -> Result<(i32, bool, String), anyhow::Error> {
-//     let url = "https://passport.bilibili.com/x/passport-login/web/cookie/info";
-//     let headers = create_headers(cookie);
-//     let mut params: HashMap<&str, &str> = HashMap::new();
-//     params.insert("csrf", cookie.bili_jct.as_str());
-//     let resp: String = client
-//         .get(url)
-//         .headers(headers.clone())
-//         .query(&params)
-//         .send()
-//         .await?
-//         .text()
-//         .await?;
-//     let resp_url: Value = serde_json::from_str(&resp)?;
-//     //println!("{}", resp_url);
-//     let code: i32 = resp_url["code"].as_i64().unwrap_or(-1) as i32;
-//     let fefresh: bool = resp_url["data"]["refresh"].as_bool().unwrap_or(true);
-//     let timestamp: String = resp_url["data"]["timestamp"]
-//         .as_i64()
-//         .unwrap_or(0)
-//         .to_string();
-
-//     Ok((code, fefresh, timestamp))
-// }
-
-/*
-async fn get_refresh_csrf(
-    correspond_path: &str,
-    client: &Client,
-    cookie: &Cookies,
-) -> Result<String, anyhow::Error> {
-    Ok("".to_string())
-}
-
-fn correspond_path(timestamp: &str) -> Result<String> {
-    let pubkey_pem = r#"-----BEGIN PUBLIC KEY-----
-MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDLgd2OAkcGVtoE3ThUREbio0Eg
-Uc/prcajMKXvkCKFCWhJYJcLkcM2DKKcSeFpD/j6Boy538YXnR6VhcuUJOhH2x71
-nzPjfdTcqMz7djHum0qSZA0AyCBDABUqCrfNgCiJ00Ra7GmRj+YCK1NJEuewlb40
-JNrRuoEUXpabUzGB8QIDAQAB
------END PUBLIC KEY-----"#;
-    let pubkey = RsaPublicKey::from_public_key_pem(pubkey_pem)?;
-    let timestamp = format!("refresh_{}", timestamp);
-    let padding = Oaep::new::<Sha256>();
+/// 检查接口返回的code，不为0时返回错误
+fn check_code(json: &Value, api: &str) -> Result<()> {
+    let code = json["code"].as_i64().unwrap_or(-1);
+    if code != 0 {
+        return Err(anyhow!(
+            "{} failed with code {}: {}",
+            api,
+            code,
+            json["message"].as_str().unwrap_or("")
+        ));
+    }
+    Ok(())
+}
 
+/// 判断是否需要刷新cookie，返回(是否需要刷新, 服务器时间戳毫秒)
+async fn is_need_refresh(client: &Client, cookie: &Cookies) -> Result<(bool, i64)> {
+    let url = "https://passport.bilibili.com/x/passport-login/web/cookie/info";
+    let json: Value = client
+        .get(url)
+        .headers(create_headers())
+        .query(&[("csrf", cookie.bili_jct.as_str())])
+        .send()
+        .await?
+        .json()
+        .await
+        .context("Failed to parse cookie info response")?;
+    check_code(&json, "cookie/info")?;
+    Ok((
+        json["data"]["refresh"].as_bool().unwrap_or(false),
+        json["data"]["timestamp"].as_i64().unwrap_or(0),
+    ))
+}
+
+/// 使用RSA-OAEP加密 `refresh_{timestamp}`，得到十六进制的correspondPath
+fn correspond_path(timestamp: i64) -> Result<String> {
+    let pubkey = RsaPublicKey::from_public_key_pem(REFRESH_PUBKEY)?;
+    let message = format!("refresh_{}", timestamp);
+    let padding = Oaep::new::<Sha256>();
     let mut rng = rand::thread_rng();
-    let encrypted = pubkey.encrypt(&mut rng, padding, timestamp.as_bytes())?;
-    let encrypted_hex = hex::encode(encrypted);
-    Ok(encrypted_hex)
+    let encrypted = pubkey.encrypt(&mut rng, padding, message.as_bytes())?;
+    Ok(hex::encode(encrypted))
 }
 
-#[tokio::test]
-async fn test_csrf() {
-    let client: Client = reqwest::Client::builder()
-        .cookie_store(true)
-        .build()
-        .unwrap();
+/// 从correspond页面获取refresh_csrf
+async fn get_refresh_csrf(client: &Client, correspond_path: &str) -> Result<String> {
+    let url = format!("https://www.bilibili.com/correspond/1/{}", correspond_path);
+    let html = client
+        .get(url)
+        .headers(create_headers())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    parse_refresh_csrf(&html).context("refresh_csrf not found in correspond page")
+}
 
-    let path = Path::new("load");
-    let cookie = read_cookie(path);
-    let (x, y, timestamp) = is_need_refresh(&client, &cookie).await.unwrap();
-    //let timestamp = "1734095039907";
-    let encrypted_hex = correspond_path(&timestamp).unwrap();
-    println!("\n{}", encrypted_hex);
+/// refresh_csrf在 `<div id="1-name">` 中
+fn parse_refresh_csrf(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse(r#"div[id="1-name"]"#).ok()?;
+    let csrf = document
+        .select(&selector)
+        .next()?
+        .text()
+        .collect::<String>();
+    let csrf = csrf.trim();
+    (!csrf.is_empty()).then(|| csrf.to_string())
+}
 
-    let csrf = get_refresh_csrf(&encrypted_hex, &client, &cookie)
+/// 刷新cookie，新的cookie写入客户端的jar，返回新的refresh_token
+async fn refresh_request(client: &Client, cookie: &Cookies, refresh_csrf: &str) -> Result<String> {
+    let url = "https://passport.bilibili.com/x/passport-login/web/cookie/refresh";
+    let json: Value = client
+        .post(url)
+        .headers(create_headers())
+        .form(&[
+            ("csrf", cookie.bili_jct.as_str()),
+            ("refresh_csrf", refresh_csrf),
+            ("source", "main_web"),
+            ("refresh_token", cookie.refresh_token.as_str()),
+        ])
+        .send()
+        .await?
+        .json()
         .await
-        .unwrap();
-    println!("\n{}", csrf);
+        .context("Failed to parse cookie refresh response")?;
+    check_code(&json, "cookie/refresh")?;
+    let refresh_token = json["data"]["refresh_token"]
+        .as_str()
+        .filter(|t| !t.is_empty())
+        .context("Refresh response does not contain refresh_token")?;
+    Ok(refresh_token.to_string())
+}
+
+/// 使用新的cookie确认刷新，使旧的refresh_token失效
+async fn confirm_refresh(client: &Client, csrf: &str, old_refresh_token: &str) -> Result<()> {
+    let url = "https://passport.bilibili.com/x/passport-login/web/confirm/refresh";
+    let json: Value = client
+        .post(url)
+        .headers(create_headers())
+        .form(&[("csrf", csrf), ("refresh_token", old_refresh_token)])
+        .send()
+        .await?
+        .json()
+        .await
+        .context("Failed to parse confirm refresh response")?;
+    check_code(&json, "confirm/refresh")
 }
 
 #[test]
 fn test_correspond_path() {
-    let timestamp = "1734097847297";
-    let encrypted_hex = correspond_path(&timestamp).unwrap();
-    println!("\n{}", encrypted_hex);
+    let encrypted_hex = correspond_path(1734097847297).unwrap();
+    // 1024位密钥，密文128字节
+    assert_eq!(encrypted_hex.len(), 256);
+    // OAEP带随机填充，每次结果不同
+    assert_ne!(encrypted_hex, correspond_path(1734097847297).unwrap());
+}
+
+#[test]
+fn test_refresh_parsing() {
+    let html =
+        r#"<html><body><div id="1-name">b0cc8411ded2f9db2cff2edb3123acac</div></body></html>"#;
+    assert_eq!(
+        parse_refresh_csrf(html).as_deref(),
+        Some("b0cc8411ded2f9db2cff2edb3123acac")
+    );
+    assert_eq!(parse_refresh_csrf("<div id=\"2-name\">x</div>"), None);
+}
+
+#[test]
+fn test_cookie_jar() {
+    let cookie: Cookies = serde_json::from_str(
+        r#"{"SESSDATA":"old","bili_jct":"jct","refresh_token":"token","DedeUserID":"1",
+            "gourl":"https%3A%2F%2Fwww.bilibili.com"}"#,
+    )
+    .unwrap();
+    assert_eq!(cookie.extra["DedeUserID"], "1");
+    let pairs = cookie.pairs();
+    assert!(pairs.contains(&("bili_jct", "jct")));
+    assert!(!pairs.iter().any(|(name, _)| *name == "gourl"));
+
+    let jar = cookie.to_jar();
+    // 模拟刷新响应的Set-Cookie
+    jar.add_cookie_str(
+        "SESSDATA=a%2Cb; Path=/; Domain=.bilibili.com; HttpOnly",
+        &Url::parse("https://passport.bilibili.com").unwrap(),
+    );
+    let header = jar
+        .cookies(&Url::parse("https://api.bilibili.com/x/web-interface/nav").unwrap())
+        .unwrap();
+    assert!(header.to_str().unwrap().contains("DedeUserID=1"));
+
+    let refreshed = Cookies::from_jar(&jar, "new");
+    assert_eq!(refreshed.SESSDATA, "a%2Cb");
+    assert_eq!(refreshed.bili_jct, "jct");
+    assert_eq!(refreshed.refresh_token, "new");
+    assert_eq!(refreshed.extra.get("gourl"), None);
+    let json = serde_json::to_value(&refreshed).unwrap();
+    assert_eq!(json["DedeUserID"], "1");
+    assert_eq!(json["refresh_token"], "new");
 }
-*/
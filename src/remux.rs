@@ -0,0 +1,1068 @@
+/// 将B站DASH的fMP4(m4s)文件合并为普通MP4(moov在前，即faststart)，不依赖ffmpeg
+///
+/// 每个输入文件只取第一条轨道，样本数据直接从输入复制到输出的mdat中；
+/// 也可以边下载边合并(StreamMuxer)，此时moov写在文件末尾
+use crate::chapter::Chapter;
+use crate::metadata::Metadata;
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// 单个样本在输入文件中的位置和时间信息
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    pub duration: u32,
+    pub cto: i32,
+    pub sync: bool,
+}
+
+/// trex中的默认值
+#[derive(Debug, Clone, Default)]
+pub struct Trex {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+/// 一条轨道，trak为输入moov中的原始trak盒子
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub trak: Vec<u8>,
+    pub track_id: u32,
+    pub timescale: u32,
+    pub trex: Trex,
+    pub samples: Vec<Sample>,
+    /// 每个trun对应输出中的一个chunk: (第一个样本下标, 样本数)
+    pub runs: Vec<(usize, usize)>,
+}
+
+impl Track {
+    /// 媒体时长(轨道时间刻度)
+    pub fn duration(&self) -> u64 {
+        self.samples.iter().map(|s| s.duration as u64).sum()
+    }
+}
+
+pub struct BoxRef<'a> {
+    pub kind: [u8; 4],
+    pub body: &'a [u8],
+    pub raw: &'a [u8],
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos + n;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| anyhow!("Unexpected end of box"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+    /// 读取full box的version和flags
+    fn version_flags(&mut self) -> Result<(u8, u32)> {
+        let v = self.u32()?;
+        Ok(((v >> 24) as u8, v & 0x00ff_ffff))
+    }
+}
+
+/// 解析一段数据中的全部子盒子
+pub fn children(data: &[u8]) -> Result<Vec<BoxRef<'_>>> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut r = Reader::new(&data[pos..]);
+        let mut size = r.u32()? as u64;
+        let kind: [u8; 4] = r.take(4)?.try_into()?;
+        let mut header = 8;
+        if size == 1 {
+            size = r.u64()?;
+            header = 16;
+        } else if size == 0 {
+            size = (data.len() - pos) as u64;
+        }
+        // 损坏的64位大小可能接近u64::MAX，相加时需要检查溢出
+        let in_range = (pos as u64)
+            .checked_add(size)
+            .is_some_and(|end| end <= data.len() as u64);
+        if size < header || !in_range {
+            return Err(anyhow!(
+                "Invalid box {} at {}",
+                String::from_utf8_lossy(&kind),
+                pos
+            ));
+        }
+        let end = pos + size as usize;
+        boxes.push(BoxRef {
+            kind,
+            body: &data[pos + header as usize..end],
+            raw: &data[pos..end],
+        });
+        pos = end;
+    }
+    Ok(boxes)
+}
+
+pub fn find<'a, 'b>(boxes: &'b [BoxRef<'a>], kind: &[u8; 4]) -> Option<&'b BoxRef<'a>> {
+    boxes.iter().find(|b| &b.kind == kind)
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<&'a [u8]> {
+    children(data)?
+        .into_iter()
+        .find(|b| &b.kind == kind)
+        .map(|b| b.body)
+        .ok_or_else(|| anyhow!("Missing {} box", String::from_utf8_lossy(kind)))
+}
+
+/// 读取pos处的顶层盒子头部，返回(类型, 头部长度, 盒子总长度)
+pub fn read_box_header<R: Read>(input: &mut R, pos: u64, len: u64) -> Result<([u8; 4], u64, u64)> {
+    let mut header = [0u8; 8];
+    input.read_exact(&mut header)?;
+    let mut size = u32::from_be_bytes(header[0..4].try_into()?) as u64;
+    let kind: [u8; 4] = header[4..8].try_into()?;
+    let mut header_len = 8;
+    if size == 1 {
+        let mut large = [0u8; 8];
+        input.read_exact(&mut large)?;
+        size = u64::from_be_bytes(large);
+        header_len = 16;
+    } else if size == 0 {
+        size = len - pos;
+    }
+    let in_range = pos.checked_add(size).is_some_and(|end| end <= len);
+    if size < header_len || !in_range {
+        return Err(anyhow!(
+            "Invalid top-level box {} at {}",
+            String::from_utf8_lossy(&kind),
+            pos
+        ));
+    }
+    Ok((kind, header_len, size))
+}
+
+/// 从moov中取出第一条轨道
+pub fn parse_moov(moov: &[u8]) -> Result<Track> {
+    let boxes = children(moov)?;
+    let trak = find(&boxes, b"trak").context("Missing trak box")?;
+    let tkhd = child(trak.body, b"tkhd")?;
+    let mut r = Reader::new(tkhd);
+    let (version, _) = r.version_flags()?;
+    r.take(if version == 1 { 16 } else { 8 })?;
+    let track_id = r.u32()?;
+
+    let mdhd = child(child(trak.body, b"mdia")?, b"mdhd")?;
+    let mut r = Reader::new(mdhd);
+    let (version, _) = r.version_flags()?;
+    r.take(if version == 1 { 16 } else { 8 })?;
+    let timescale = r.u32()?;
+
+    let mut trex = Trex::default();
+    if let Some(mvex) = find(&boxes, b"mvex") {
+        for b in children(mvex.body)? {
+            if &b.kind != b"trex" {
+                continue;
+            }
+            let mut r = Reader::new(b.body);
+            r.version_flags()?;
+            if r.u32()? != track_id {
+                continue;
+            }
+            r.u32()?;
+            trex = Trex {
+                duration: r.u32()?,
+                size: r.u32()?,
+                flags: r.u32()?,
+            };
+        }
+    }
+    Ok(Track {
+        trak: trak.raw.to_vec(),
+        track_id,
+        timescale,
+        trex,
+        samples: Vec::new(),
+        runs: Vec::new(),
+    })
+}
+
+fn is_sync(flags: u32) -> bool {
+    flags & 0x0001_0000 == 0
+}
+
+/// 解析moof，将样本追加到轨道中；moof_offset为moof在输入文件中的起始位置
+pub fn parse_moof(track: &mut Track, moof: &[u8], moof_offset: u64) -> Result<()> {
+    let mut next_base = moof_offset;
+    for traf in children(moof)?.iter().filter(|b| &b.kind == b"traf") {
+        let boxes = children(traf.body)?;
+        let tfhd = find(&boxes, b"tfhd").context("Missing tfhd box")?;
+        let mut r = Reader::new(tfhd.body);
+        let (_, flags) = r.version_flags()?;
+        if r.u32()? != track.track_id {
+            continue;
+        }
+        let mut base = next_base;
+        let mut duration = track.trex.duration;
+        let mut size = track.trex.size;
+        let mut sample_flags = track.trex.flags;
+        if flags & 0x01 != 0 {
+            base = r.u64()?;
+        } else if flags & 0x02_0000 != 0 {
+            base = moof_offset;
+        }
+        if flags & 0x02 != 0 {
+            r.u32()?;
+        }
+        if flags & 0x08 != 0 {
+            duration = r.u32()?;
+        }
+        if flags & 0x10 != 0 {
+            size = r.u32()?;
+        }
+        if flags & 0x20 != 0 {
+            sample_flags = r.u32()?;
+        }
+
+        let mut data_pos = base;
+        for trun in boxes.iter().filter(|b| &b.kind == b"trun") {
+            let mut r = Reader::new(trun.body);
+            let (version, tr_flags) = r.version_flags()?;
+            let count = r.u32()? as usize;
+            if tr_flags & 0x01 != 0 {
+                let offset = r.u32()? as i32;
+                data_pos = (base as i64 + offset as i64) as u64;
+            }
+            let first_flags = if tr_flags & 0x04 != 0 {
+                Some(r.u32()?)
+            } else {
+                None
+            };
+            let first = track.samples.len();
+            for i in 0..count {
+                let s_duration = if tr_flags & 0x100 != 0 {
+                    r.u32()?
+                } else {
+                    duration
+                };
+                let s_size = if tr_flags & 0x200 != 0 {
+                    r.u32()?
+                } else {
+                    size
+                };
+                let mut s_flags = if tr_flags & 0x400 != 0 {
+                    r.u32()?
+                } else {
+                    sample_flags
+                };
+                if i == 0 {
+                    if let Some(f) = first_flags {
+                        s_flags = f;
+                    }
+                }
+                let cto = if tr_flags & 0x800 != 0 {
+                    let v = r.u32()?;
+                    if version == 0 && v > i32::MAX as u32 {
+                        return Err(anyhow!("Composition offset out of range"));
+                    }
+                    v as i32
+                } else {
+                    0
+                };
+                track.samples.push(Sample {
+                    offset: data_pos,
+                    size: s_size,
+                    duration: s_duration,
+                    cto,
+                    sync: is_sync(s_flags),
+                });
+                data_pos += s_size as u64;
+            }
+            if count > 0 {
+                track.runs.push((first, count));
+            }
+        }
+        next_base = data_pos;
+    }
+    Ok(())
+}
+
+/// 读取一个fMP4文件中的轨道和全部样本
+pub fn read_fragmented(path: &Path) -> Result<Track> {
+    let mut input = BufReader::new(File::open(path)?);
+    let len = input.get_ref().metadata()?.len();
+    let mut pos = 0;
+    let mut track: Option<Track> = None;
+    while pos < len {
+        input.seek(SeekFrom::Start(pos))?;
+        let (kind, header_len, size) = read_box_header(&mut input, pos, len)?;
+        if &kind == b"moov" || &kind == b"moof" {
+            // 大小来自文件内容，分配前确认不超过文件的剩余长度
+            if size > len - pos {
+                return Err(anyhow!("Box at {} exceeds file length {}", pos, len));
+            }
+            let mut body = vec![0u8; (size - header_len) as usize];
+            input.read_exact(&mut body)?;
+            if &kind == b"moov" {
+                track = Some(parse_moov(&body)?);
+            } else {
+                let track = track.as_mut().context("moof before moov")?;
+                parse_moof(track, &body, pos)?;
+            }
+        }
+        pos += size;
+    }
+    let track = track.with_context(|| format!("No moov box in {}", path.display()))?;
+    if track.samples.is_empty() {
+        return Err(anyhow!("No samples in {}", path.display()));
+    }
+    for s in &track.samples {
+        if s.offset + s.size as u64 > len {
+            return Err(anyhow!("Sample data out of range in {}", path.display()));
+        }
+    }
+    Ok(track)
+}
+
+pub fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(&((body.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+pub fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(body.len() + 4);
+    data.extend_from_slice(&(((version as u32) << 24) | flags).to_be_bytes());
+    data.extend_from_slice(body);
+    mp4_box(kind, &data)
+}
+
+/// 游程编码(stts/ctts)
+fn run_length<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for v in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == v => *count += 1,
+            _ => runs.push((1, v)),
+        }
+    }
+    runs
+}
+
+fn build_stbl(stsd: &[u8], track: &Track, chunk_offsets: &[u64], large: bool) -> Vec<u8> {
+    let mut stbl = stsd.to_vec();
+
+    let stts = run_length(track.samples.iter().map(|s| s.duration));
+    let mut body = (stts.len() as u32).to_be_bytes().to_vec();
+    for (count, duration) in stts {
+        body.extend_from_slice(&count.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+    }
+    stbl.extend(full_box(b"stts", 0, 0, &body));
+
+    if track.samples.iter().any(|s| s.cto != 0) {
+        let negative = track.samples.iter().any(|s| s.cto < 0);
+        let ctts = run_length(track.samples.iter().map(|s| s.cto));
+        let mut body = (ctts.len() as u32).to_be_bytes().to_vec();
+        for (count, cto) in ctts {
+            body.extend_from_slice(&count.to_be_bytes());
+            body.extend_from_slice(&cto.to_be_bytes());
+        }
+        stbl.extend(full_box(b"ctts", negative as u8, 0, &body));
+    }
+
+    if track.samples.iter().any(|s| !s.sync) {
+        let sync: Vec<u32> = (1..=track.samples.len() as u32)
+            .filter(|&i| track.samples[i as usize - 1].sync)
+            .collect();
+        let mut body = (sync.len() as u32).to_be_bytes().to_vec();
+        for i in sync {
+            body.extend_from_slice(&i.to_be_bytes());
+        }
+        stbl.extend(full_box(b"stss", 0, 0, &body));
+    }
+
+    let mut stsc: Vec<(u32, u32)> = Vec::new();
+    for (i, (_, count)) in track.runs.iter().enumerate() {
+        if stsc.last().map(|&(_, c)| c) != Some(*count as u32) {
+            stsc.push((i as u32 + 1, *count as u32));
+        }
+    }
+    let mut body = (stsc.len() as u32).to_be_bytes().to_vec();
+    for (first, count) in stsc {
+        body.extend_from_slice(&first.to_be_bytes());
+        body.extend_from_slice(&count.to_be_bytes());
+        body.extend_from_slice(&1u32.to_be_bytes());
+    }
+    stbl.extend(full_box(b"stsc", 0, 0, &body));
+
+    let mut body = 0u32.to_be_bytes().to_vec();
+    body.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+    for s in &track.samples {
+        body.extend_from_slice(&s.size.to_be_bytes());
+    }
+    stbl.extend(full_box(b"stsz", 0, 0, &body));
+
+    let mut body = (chunk_offsets.len() as u32).to_be_bytes().to_vec();
+    for &offset in chunk_offsets {
+        if large {
+            body.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            body.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+    stbl.extend(full_box(if large { b"co64" } else { b"stco" }, 0, 0, &body));
+
+    mp4_box(b"stbl", &stbl)
+}
+
+/// 修改tkhd/mdhd中的时长，version 1时字段为64位
+fn patch_duration(body: &[u8], duration: u64, track_id: Option<u32>) -> Result<Vec<u8>> {
+    let mut body = body.to_vec();
+    let version = *body.first().context("Empty box")?;
+    let (id_pos, duration_pos) = match (version, track_id.is_some()) {
+        (1, true) => (20, 28),
+        (_, true) => (12, 20),
+        (1, false) => (0, 24),
+        (_, false) => (0, 16),
+    };
+    if body.len() < duration_pos + if version == 1 { 8 } else { 4 } {
+        return Err(anyhow!("Invalid tkhd/mdhd box"));
+    }
+    if let Some(id) = track_id {
+        body[id_pos..id_pos + 4].copy_from_slice(&id.to_be_bytes());
+    }
+    if version == 1 {
+        body[duration_pos..duration_pos + 8].copy_from_slice(&duration.to_be_bytes());
+    } else {
+        let duration = duration.min(u32::MAX as u64) as u32;
+        body[duration_pos..duration_pos + 4].copy_from_slice(&duration.to_be_bytes());
+    }
+    Ok(body)
+}
+
+const MOVIE_TIMESCALE: u64 = 1000;
+
+fn build_trak(track: &Track, track_id: u32, chunk_offsets: &[u64], large: bool) -> Result<Vec<u8>> {
+    let media_duration = track.duration();
+    let movie_duration = media_duration * MOVIE_TIMESCALE / track.timescale.max(1) as u64;
+    let trak = children(&track.trak)?;
+    let trak_body = trak.first().context("Missing trak box")?.body;
+    let mut out = Vec::new();
+    let mut has_edts = false;
+    for b in children(trak_body)? {
+        match &b.kind {
+            b"tkhd" => out.extend(mp4_box(
+                b"tkhd",
+                &patch_duration(b.body, movie_duration, Some(track_id))?,
+            )),
+            b"edts" => {
+                has_edts = true;
+                out.extend_from_slice(b.raw);
+            }
+            b"mdia" => {
+                // 第一帧有显示时间偏移时补充编辑列表，避免音画不同步
+                let first_cto = track.samples.first().map(|s| s.cto).unwrap_or(0);
+                if !has_edts && first_cto > 0 {
+                    let mut elst = 1u32.to_be_bytes().to_vec();
+                    elst.extend_from_slice(&(movie_duration as u32).to_be_bytes());
+                    elst.extend_from_slice(&first_cto.to_be_bytes());
+                    elst.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+                    out.extend(mp4_box(b"edts", &full_box(b"elst", 0, 0, &elst)));
+                }
+                let mut mdia = Vec::new();
+                for m in children(b.body)? {
+                    match &m.kind {
+                        b"mdhd" => mdia.extend(mp4_box(
+                            b"mdhd",
+                            &patch_duration(m.body, media_duration, None)?,
+                        )),
+                        b"minf" => {
+                            let mut minf = Vec::new();
+                            for n in children(m.body)? {
+                                if &n.kind == b"stbl" {
+                                    let stsd = children(n.body)?
+                                        .into_iter()
+                                        .find(|s| &s.kind == b"stsd")
+                                        .map(|s| s.raw.to_vec())
+                                        .context("Missing stsd box")?;
+                                    minf.extend(build_stbl(&stsd, track, chunk_offsets, large));
+                                } else {
+                                    minf.extend_from_slice(n.raw);
+                                }
+                            }
+                            mdia.extend(mp4_box(b"minf", &minf));
+                        }
+                        _ => mdia.extend_from_slice(m.raw),
+                    }
+                }
+                out.extend(mp4_box(b"mdia", &mdia));
+            }
+            _ => out.extend_from_slice(b.raw),
+        }
+    }
+    Ok(mp4_box(b"trak", &out))
+}
+
+fn build_mvhd(duration: u64, next_track_id: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&[0u8; 8]); // creation/modification time
+    body.extend_from_slice(&(MOVIE_TIMESCALE as u32).to_be_bytes());
+    body.extend_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes());
+    body.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate
+    body.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    body.extend_from_slice(&[0u8; 10]);
+    for m in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        body.extend_from_slice(&m.to_be_bytes());
+    }
+    body.extend_from_slice(&[0u8; 24]);
+    body.extend_from_slice(&next_track_id.to_be_bytes());
+    full_box(b"mvhd", 0, 0, &body)
+}
+
+pub fn build_ftyp() -> Vec<u8> {
+    let mut body = b"isom".to_vec();
+    body.extend_from_slice(&0x200u32.to_be_bytes());
+    for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+        body.extend_from_slice(brand);
+    }
+    mp4_box(b"ftyp", &body)
+}
+
+fn ilst_item(kind: &[u8; 4], data_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = data_type.to_be_bytes().to_vec();
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(payload);
+    mp4_box(kind, &mp4_box(b"data", &data))
+}
+
+/// Nero风格的章节(chpl)，时间单位为100纳秒，最多255个
+fn build_chpl(chapters: &[Chapter]) -> Vec<u8> {
+    let chapters = &chapters[..chapters.len().min(255)];
+    let mut body = vec![0u8; 4];
+    body.push(chapters.len() as u8);
+    for c in chapters {
+        body.extend_from_slice(&(c.start * 10_000_000).to_be_bytes());
+        let mut title = c.title.as_str();
+        while title.len() > 255 {
+            let mut end = title.len() - 1;
+            while !title.is_char_boundary(end) {
+                end -= 1;
+            }
+            title = &title[..end];
+        }
+        body.push(title.len() as u8);
+        body.extend_from_slice(title.as_bytes());
+    }
+    full_box(b"chpl", 1, 0, &body)
+}
+
+/// 生成iTunes风格的元数据(udta/meta/ilst)和章节，cover为jpg/png封面数据；都没有时为空
+pub fn build_udta(metadata: &Metadata, cover: &[u8]) -> Vec<u8> {
+    let mut ilst = Vec::new();
+    for (name, value) in metadata.text_tags() {
+        let kind: &[u8; 4] = match name {
+            "title" => b"\xa9nam",
+            "artist" => b"\xa9ART",
+            "date" => b"\xa9day",
+            "description" => b"desc",
+            "comment" => b"\xa9cmt",
+            "keywords" => b"keyw",
+            "episode_id" => b"tven",
+            "show" => b"tvsh",
+            "album" => b"\xa9alb",
+            _ => continue,
+        };
+        // 1: UTF-8文本
+        ilst.extend(ilst_item(kind, 1, value.as_bytes()));
+    }
+    // 21: 大端整数
+    if metadata.season > 0 {
+        ilst.extend(ilst_item(b"tvsn", 21, &metadata.season.to_be_bytes()));
+    }
+    if metadata.episode > 0 {
+        ilst.extend(ilst_item(b"tves", 21, &metadata.episode.to_be_bytes()));
+    }
+    if !cover.is_empty() {
+        // 13: JPEG, 14: PNG
+        let data_type = if cover.starts_with(b"\x89PNG") {
+            14
+        } else {
+            13
+        };
+        ilst.extend(ilst_item(b"covr", data_type, cover));
+    }
+    let mut udta = Vec::new();
+    if !ilst.is_empty() {
+        let mut hdlr = 0u32.to_be_bytes().to_vec();
+        hdlr.extend_from_slice(b"mdirappl");
+        hdlr.extend_from_slice(&[0u8; 9]);
+        let meta = [full_box(b"hdlr", 0, 0, &hdlr), mp4_box(b"ilst", &ilst)].concat();
+        udta.extend(full_box(b"meta", 0, 0, &meta));
+    }
+    if !metadata.chapters.is_empty() {
+        udta.extend(build_chpl(&metadata.chapters));
+    }
+    if udta.is_empty() {
+        return Vec::new();
+    }
+    mp4_box(b"udta", &udta)
+}
+
+/// 生成moov，chunk_offsets[i]为第i条轨道每个chunk在输出文件中的位置
+pub fn build_moov(
+    tracks: &[Track],
+    chunk_offsets: &[Vec<u64>],
+    large: bool,
+    udta: &[u8],
+) -> Result<Vec<u8>> {
+    let duration = tracks
+        .iter()
+        .map(|t| t.duration() * MOVIE_TIMESCALE / t.timescale.max(1) as u64)
+        .max()
+        .unwrap_or(0);
+    let mut moov = build_mvhd(duration, tracks.len() as u32 + 1);
+    for (i, track) in tracks.iter().enumerate() {
+        moov.extend(build_trak(track, i as u32 + 1, &chunk_offsets[i], large)?);
+    }
+    moov.extend_from_slice(udta);
+    Ok(mp4_box(b"moov", &moov))
+}
+
+/// 按解码时间交错各轨道的chunk，返回(轨道下标, run下标)
+pub fn interleave(tracks: &[Track]) -> Vec<(usize, usize)> {
+    let mut order: Vec<(f64, usize, usize)> = Vec::new();
+    for (t, track) in tracks.iter().enumerate() {
+        let mut dts = 0u64;
+        for (r, &(first, count)) in track.runs.iter().enumerate() {
+            order.push((dts as f64 / track.timescale.max(1) as f64, t, r));
+            dts += track.samples[first..first + count]
+                .iter()
+                .map(|s| s.duration as u64)
+                .sum::<u64>();
+        }
+    }
+    order.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    order.into_iter().map(|(_, t, r)| (t, r)).collect()
+}
+
+fn run_size(track: &Track, run: usize) -> u64 {
+    let (first, count) = track.runs[run];
+    track.samples[first..first + count]
+        .iter()
+        .map(|s| s.size as u64)
+        .sum()
+}
+
+/// 合并多个fMP4文件(如视频和音频m4s)为一个MP4，并写入元数据和封面
+pub fn remux(
+    inputs: &[&Path],
+    output: &Path,
+    metadata: &Metadata,
+    cover: Option<&Path>,
+) -> Result<()> {
+    let tracks = inputs
+        .iter()
+        .map(|p| read_fragmented(p))
+        .collect::<Result<Vec<Track>>>()?;
+    let order = interleave(&tracks);
+    let data_size: u64 = order.iter().map(|&(t, r)| run_size(&tracks[t], r)).sum();
+    let large = data_size > u32::MAX as u64 - (64 << 20);
+    let mdat_header: u64 = if large { 16 } else { 8 };
+
+    let ftyp = build_ftyp();
+    let cover = match cover {
+        Some(path) => std::fs::read(path)?,
+        None => Vec::new(),
+    };
+    let udta = build_udta(metadata, &cover);
+    let empty: Vec<Vec<u64>> = tracks.iter().map(|t| vec![0; t.runs.len()]).collect();
+    let moov_size = build_moov(&tracks, &empty, large, &udta)?.len() as u64;
+
+    let mut offsets = empty;
+    let mut pos = ftyp.len() as u64 + moov_size + mdat_header;
+    for &(t, r) in &order {
+        offsets[t][r] = pos;
+        pos += run_size(&tracks[t], r);
+    }
+    let moov = build_moov(&tracks, &offsets, large, &udta)?;
+
+    let mut out = BufWriter::new(File::create(output)?);
+    out.write_all(&ftyp)?;
+    out.write_all(&moov)?;
+    if large {
+        out.write_all(&1u32.to_be_bytes())?;
+        out.write_all(b"mdat")?;
+        out.write_all(&(data_size + 16).to_be_bytes())?;
+    } else {
+        out.write_all(&((data_size + 8) as u32).to_be_bytes())?;
+        out.write_all(b"mdat")?;
+    }
+    let mut files = inputs
+        .iter()
+        .map(|p| File::open(p).map(BufReader::new))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    for &(t, r) in &order {
+        let (first, _) = tracks[t].runs[r];
+        let input = &mut files[t];
+        input.seek(SeekFrom::Start(tracks[t].samples[first].offset))?;
+        let size = run_size(&tracks[t], r);
+        let copied = std::io::copy(&mut input.by_ref().take(size), &mut out)?;
+        if copied != size {
+            return Err(anyhow!("Unexpected end of input stream"));
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// 下载中的fMP4里的一个完整片段
+pub enum Segment {
+    /// moov的内容
+    Init(Vec<u8>),
+    /// moof及紧随其后的mdat，offset为在输入流中的位置
+    Fragment {
+        moof: Vec<u8>,
+        moof_offset: u64,
+        data: Vec<u8>,
+        data_offset: u64,
+    },
+}
+
+/// 增量解析下载中的fMP4，凑齐完整的顶层盒子后输出片段
+#[derive(Default)]
+pub struct SegmentReader {
+    buf: Vec<u8>,
+    /// buf[0]在输入流中的位置
+    pos: u64,
+    moof: Option<(Vec<u8>, u64)>,
+}
+
+impl SegmentReader {
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Segment>> {
+        self.buf.extend_from_slice(data);
+        let mut segments = Vec::new();
+        let mut start = 0;
+        while self.buf.len() - start >= 8 {
+            let rest = &self.buf[start..];
+            let mut size = u32::from_be_bytes(rest[0..4].try_into()?) as u64;
+            let kind: [u8; 4] = rest[4..8].try_into()?;
+            let mut header_len = 8;
+            if size == 1 {
+                if rest.len() < 16 {
+                    break;
+                }
+                size = u64::from_be_bytes(rest[8..16].try_into()?);
+                header_len = 16;
+            }
+            let offset = self.pos + start as u64;
+            if size < header_len {
+                return Err(anyhow!(
+                    "Invalid top-level box {} at {}",
+                    String::from_utf8_lossy(&kind),
+                    offset
+                ));
+            }
+            if (rest.len() as u64) < size {
+                break;
+            }
+            let body = rest[header_len as usize..size as usize].to_vec();
+            match &kind {
+                b"moov" => segments.push(Segment::Init(body)),
+                b"moof" => self.moof = Some((body, offset)),
+                b"mdat" => {
+                    if let Some((moof, moof_offset)) = self.moof.take() {
+                        segments.push(Segment::Fragment {
+                            moof,
+                            moof_offset,
+                            data: body,
+                            data_offset: offset + header_len,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            start += size as usize;
+        }
+        self.buf.drain(..start);
+        self.pos += start as u64;
+        Ok(segments)
+    }
+
+    /// 输入结束，检查是否有不完整的盒子
+    pub fn finish(&self) -> Result<()> {
+        if !self.buf.is_empty() {
+            return Err(anyhow!("Stream ended inside a box at {}", self.pos));
+        }
+        Ok(())
+    }
+}
+
+/// 边下载边合并：片段的样本数据到达后直接写入mdat，结束时在末尾写入moov
+pub struct StreamMuxer {
+    out: BufWriter<File>,
+    tracks: Vec<Option<Track>>,
+    offsets: Vec<Vec<u64>>,
+    mdat_start: u64,
+    pos: u64,
+}
+
+impl StreamMuxer {
+    pub fn create(output: &Path, track_count: usize) -> Result<Self> {
+        let mut out = BufWriter::new(File::create(output)?);
+        let ftyp = build_ftyp();
+        out.write_all(&ftyp)?;
+        // 总长度未知，使用64位长度，结束时回填
+        out.write_all(&1u32.to_be_bytes())?;
+        out.write_all(b"mdat")?;
+        out.write_all(&0u64.to_be_bytes())?;
+        let mdat_start = ftyp.len() as u64;
+        Ok(StreamMuxer {
+            out,
+            tracks: vec![None; track_count],
+            offsets: vec![Vec::new(); track_count],
+            mdat_start,
+            pos: mdat_start + 16,
+        })
+    }
+
+    /// 写入第index个输入的片段
+    pub fn add(&mut self, index: usize, segment: Segment) -> Result<()> {
+        match segment {
+            Segment::Init(moov) => self.tracks[index] = Some(parse_moov(&moov)?),
+            Segment::Fragment {
+                moof,
+                moof_offset,
+                data,
+                data_offset,
+            } => {
+                let track = self.tracks[index].as_mut().context("moof before moov")?;
+                let runs = track.runs.len();
+                parse_moof(track, &moof, moof_offset)?;
+                for &(first, count) in &track.runs[runs..] {
+                    let last = &track.samples[first + count - 1];
+                    let start = track.samples[first].offset;
+                    if start < data_offset
+                        || last.offset + last.size as u64 > data_offset + data.len() as u64
+                    {
+                        return Err(anyhow!("Sample data outside of mdat"));
+                    }
+                    self.offsets[index].push(self.pos + start - data_offset);
+                }
+                self.out.write_all(&data)?;
+                self.pos += data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// 第index个输入已写入的时长(秒)，用于按时间交错写入
+    pub fn time(&self, index: usize) -> f64 {
+        self.tracks[index]
+            .as_ref()
+            .map(|t| t.duration() as f64 / t.timescale.max(1) as f64)
+            .unwrap_or(0.0)
+    }
+
+    /// 写入moov并回填mdat长度
+    pub fn finish(mut self, metadata: &Metadata, cover: &[u8]) -> Result<()> {
+        let tracks = self
+            .tracks
+            .into_iter()
+            .map(|t| t.context("No moov box in stream"))
+            .collect::<Result<Vec<Track>>>()?;
+        if tracks.iter().any(|t| t.samples.is_empty()) {
+            return Err(anyhow!("No samples in stream"));
+        }
+        let large = self.pos > u32::MAX as u64;
+        let udta = build_udta(metadata, cover);
+        let moov = build_moov(&tracks, &self.offsets, large, &udta)?;
+        self.out.write_all(&moov)?;
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out
+            .write_all(&(self.pos - self.mdat_start).to_be_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub fn test_fragmented(samples: &[&[u8]], timescale: u32) -> Vec<u8> {
+    let mut tkhd = vec![0u8; 80];
+    tkhd[12..16].copy_from_slice(&1u32.to_be_bytes());
+    let mut mdhd = vec![0u8; 20];
+    mdhd[12..16].copy_from_slice(&timescale.to_be_bytes());
+    let stbl = mp4_box(b"stbl", &full_box(b"stsd", 0, 0, &0u32.to_be_bytes()));
+    let mdia = [mp4_box(b"mdhd", &mdhd), mp4_box(b"minf", &stbl)].concat();
+    let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+    let trex: Vec<u8> = [1u32, 1, 1024, 0, 0x0001_0000]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect();
+    let moov = [
+        mp4_box(b"trak", &trak),
+        mp4_box(b"mvex", &full_box(b"trex", 0, 0, &trex)),
+    ]
+    .concat();
+    let mut file = [build_ftyp(), mp4_box(b"moov", &moov)].concat();
+    for (i, data) in samples.iter().enumerate() {
+        // tfhd带default_base_is_moof，trun带data_offset/size/first_sample_flags
+        let tfhd = full_box(b"tfhd", 0, 0x02_0000, &1u32.to_be_bytes());
+        let mut trun = 1u32.to_be_bytes().to_vec();
+        trun.extend_from_slice(&0u32.to_be_bytes());
+        trun.extend_from_slice(&if i == 0 { 0u32 } else { 0x0001_0000 }.to_be_bytes());
+        trun.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let trun_len = full_box(b"trun", 0, 0x205, &trun).len();
+        let moof_len = 8 + 8 + tfhd.len() + trun_len;
+        trun[4..8].copy_from_slice(&((moof_len + 8) as u32).to_be_bytes());
+        let traf = [tfhd, full_box(b"trun", 0, 0x205, &trun)].concat();
+        file.extend(mp4_box(b"moof", &mp4_box(b"traf", &traf)));
+        file.extend(mp4_box(b"mdat", data));
+    }
+    file
+}
+
+#[test]
+fn test_remux() {
+    let dir = std::env::temp_dir();
+    let video = dir.join("remux_test_video.m4s");
+    let audio = dir.join("remux_test_audio.m4s");
+    let output = dir.join("remux_test.mp4");
+    std::fs::write(&video, test_fragmented(&[b"vvvv", b"ww"], 1000)).unwrap();
+    std::fs::write(&audio, test_fragmented(&[b"aaa"], 44100)).unwrap();
+    let metadata = Metadata {
+        title: "标题".to_string(),
+        episode: 2,
+        chapters: vec![Chapter {
+            title: "开场".to_string(),
+            start: 1,
+            end: 2,
+        }],
+        ..Default::default()
+    };
+    remux(
+        &[video.as_path(), audio.as_path()],
+        &output,
+        &metadata,
+        None,
+    )
+    .unwrap();
+
+    let data = std::fs::read(&output).unwrap();
+    let top = children(&data).unwrap();
+    let kinds: Vec<&[u8; 4]> = top.iter().map(|b| &b.kind).collect();
+    assert_eq!(kinds, vec![b"ftyp", b"moov", b"mdat"]);
+    let mdat = find(&top, b"mdat").unwrap().body;
+    // 按时间交错: 视频第1段(0s)，音频(0s)，视频第2段(1.024s)
+    assert_eq!(mdat, b"vvvvaaaww");
+
+    let moov = children(find(&top, b"moov").unwrap().body).unwrap();
+    let trak = moov.iter().find(|b| &b.kind == b"trak").unwrap();
+    let stbl = child(
+        child(child(trak.body, b"mdia").unwrap(), b"minf").unwrap(),
+        b"stbl",
+    )
+    .unwrap();
+    let stbl = children(stbl).unwrap();
+    let stco = find(&stbl, b"stco").unwrap().body;
+    let mdat_start = (data.len() - mdat.len()) as u32;
+    assert_eq!(&stco[8..12], &mdat_start.to_be_bytes());
+    assert_eq!(&stco[12..16], &(mdat_start + 7).to_be_bytes());
+    let stss = find(&stbl, b"stss").unwrap().body;
+    assert_eq!(&stss[4..], &[0, 0, 0, 1, 0, 0, 0, 1]);
+
+    let udta = find(&moov, b"udta").unwrap().body;
+    let meta = children(udta).unwrap();
+    let ilst = child(&meta[0].body[4..], b"ilst").unwrap();
+    let items = children(ilst).unwrap();
+    assert_eq!(&items[0].kind, b"\xa9nam");
+    assert!(items[0].body.ends_with("标题".as_bytes()));
+    assert_eq!(&items[1].kind, b"tves");
+    let chpl = find(&meta, b"chpl").unwrap().body;
+    assert_eq!(&chpl[8..17], &[1, 0, 0, 0, 0, 0, 0x98, 0x96, 0x80]);
+    assert_eq!(&chpl[18..], "开场".as_bytes());
+
+    for p in [video, audio, output] {
+        std::fs::remove_file(p).unwrap();
+    }
+}
+
+#[test]
+fn test_stream_muxer() {
+    let output = std::env::temp_dir().join("remux_test_stream.mp4");
+    let inputs = [
+        test_fragmented(&[b"vvvv", b"ww"], 1000),
+        test_fragmented(&[b"aaa"], 44100),
+    ];
+    let mut muxer = StreamMuxer::create(&output, 2).unwrap();
+    for (i, input) in inputs.iter().enumerate() {
+        let mut reader = SegmentReader::default();
+        // 按小块输入，模拟下载
+        for chunk in input.chunks(5) {
+            for segment in reader.push(chunk).unwrap() {
+                muxer.add(i, segment).unwrap();
+            }
+        }
+        reader.finish().unwrap();
+    }
+    assert!(muxer.time(0) > 2.0);
+    muxer.finish(&Metadata::default(), &[]).unwrap();
+
+    let data = std::fs::read(&output).unwrap();
+    let top = children(&data).unwrap();
+    let kinds: Vec<&[u8; 4]> = top.iter().map(|b| &b.kind).collect();
+    assert_eq!(kinds, vec![b"ftyp", b"mdat", b"moov"]);
+    assert_eq!(find(&top, b"mdat").unwrap().body, b"vvvvwwaaa");
+    let moov = children(find(&top, b"moov").unwrap().body).unwrap();
+    let traks: Vec<&BoxRef> = moov.iter().filter(|b| &b.kind == b"trak").collect();
+    let stbl = child(
+        child(child(traks[1].body, b"mdia").unwrap(), b"minf").unwrap(),
+        b"stbl",
+    )
+    .unwrap();
+    let stco = child(stbl, b"stco").unwrap();
+    let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+    assert_eq!(&data[offset..offset + 3], b"aaa");
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn test_malformed_boxes() {
+    // 64位大小接近u64::MAX时不能溢出
+    let mut data = 1u32.to_be_bytes().to_vec();
+    data.extend_from_slice(b"moov");
+    data.extend_from_slice(&(u64::MAX - 4).to_be_bytes());
+    data.extend_from_slice(&[0u8; 8]);
+    assert!(children(&data).is_err());
+    assert!(read_box_header(&mut &data[..], 0, data.len() as u64).is_err());
+
+    // 大小超过文件长度的moov，不能按声明的大小分配内存
+    let path = std::env::temp_dir().join("remux_test_malformed.m4s");
+    let mut data = 1u32.to_be_bytes().to_vec();
+    data.extend_from_slice(b"moov");
+    data.extend_from_slice(&(1u64 << 40).to_be_bytes());
+    std::fs::write(&path, &data).unwrap();
+    assert!(read_fragmented(&path).is_err());
+    data[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    std::fs::write(&path, &data).unwrap();
+    assert!(read_fragmented(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+}
@@ -18,17 +18,24 @@ pub fn qn(s: &str) -> &str {
 }
 
 pub fn fnval(s: &str) -> &str {
-    let hash: HashMap<&str, &str> = [("HDR", "80"), ("4K", "144")].iter().cloned().collect();
+    // ALL 用于查看格式，请求全部可用的流
+    let hash: HashMap<&str, &str> = [("HDR", "80"), ("4K", "144"), ("ALL", "4048")]
+        .iter()
+        .cloned()
+        .collect();
     hash.get(s).map(|&v| v).unwrap_or("16")
 }
 
 pub fn rsl(s: &str) -> &str {
     let hash: HashMap<&str, &str> = [
+        ("127", "8K"),
+        ("126", "DolbyVision"),
         ("125", "HDR"),
         ("120", "4K"),
         ("112", "1080P+"),
         ("116", "1080P60"),
         ("80", "1080P"),
+        ("74", "720P60"),
         ("64", "720P"),
         ("32", "480P"),
         ("16", "360P"),
@@ -39,6 +46,20 @@ pub fn rsl(s: &str) -> &str {
     hash.get(s).map(|&v| v).unwrap_or("")
 }
 
+pub fn audio(s: &str) -> &str {
+    let hash: HashMap<&str, &str> = [
+        ("30251", "Hi-Res"),
+        ("30250", "Dolby"),
+        ("30280", "192K"),
+        ("30232", "132K"),
+        ("30216", "64K"),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    hash.get(s).copied().unwrap_or("")
+}
+
 #[test]
 fn x() {
     let s = "HDR";
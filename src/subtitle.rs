@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// 字幕输出格式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    #[default]
+    Srt,
+    Ass,
+    Vtt,
+}
+
+// 格式名同时也是扩展名
+named_enum!(SubtitleFormat {
+    Srt => "srt",
+    Ass => "ass",
+    Vtt => "vtt",
+});
+
+/// 播放器信息中的一条字幕
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SubtitleTrack {
+    /// 语言代码，如 zh-CN、ai-zh
+    pub lan: String,
+    /// 语言名称，如 中文（中国）
+    pub lan_doc: String,
+    /// B站JSON字幕地址
+    pub url: String,
+    /// 是否为AI生成
+    pub ai: bool,
+}
+
+/// 一条字幕，时间单位为秒
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub from: f64,
+    pub to: f64,
+    pub content: String,
+}
+
+/// 从播放器信息中获取字幕列表
+pub fn parse_subtitle_list(player: &Value) -> Vec<SubtitleTrack> {
+    player["data"]["subtitle"]["subtitles"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|s| {
+            let url = s["subtitle_url"].as_str().filter(|u| !u.is_empty())?;
+            let url = if url.starts_with("//") {
+                format!("https:{}", url)
+            } else {
+                url.to_string()
+            };
+            let lan = s["lan"].as_str().unwrap_or("");
+            Some(SubtitleTrack {
+                lan: lan.to_string(),
+                lan_doc: s["lan_doc"].as_str().unwrap_or("").to_string(),
+                url,
+                ai: lan.starts_with("ai-") || s["ai_type"].as_i64().unwrap_or(0) != 0,
+            })
+        })
+        .collect()
+}
+
+/// 按语言筛选，langs为空时返回全部
+pub fn select<'a>(tracks: &'a [SubtitleTrack], langs: &[String]) -> Vec<&'a SubtitleTrack> {
+    let langs: Vec<&String> = langs.iter().filter(|l| !l.trim().is_empty()).collect();
+    tracks
+        .iter()
+        .filter(|t| langs.is_empty() || langs.iter().any(|l| l.trim().eq_ignore_ascii_case(&t.lan)))
+        .collect()
+}
+
+/// 解析B站的JSON字幕
+pub fn parse_cues(json: &Value) -> Vec<Cue> {
+    json["body"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|c| Cue {
+            from: c["from"].as_f64().unwrap_or(0.0),
+            to: c["to"].as_f64().unwrap_or(0.0),
+            content: c["content"].as_str().unwrap_or("").to_string(),
+        })
+        .filter(|c| c.to > c.from)
+        .collect()
+}
+
+/// 将秒拆分为(时, 分, 秒, 毫秒)
+fn split_time(seconds: f64) -> (u64, u64, u64, u64) {
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+pub fn to_srt(cues: &[Cue]) -> String {
+    let time = |t: f64| {
+        let (h, m, s, ms) = split_time(t);
+        format!("{:02}:{:02}:{:02},{:03}", h, m, s, ms)
+    };
+    let mut out = String::new();
+    for (i, c) in cues.iter().enumerate() {
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            time(c.from),
+            time(c.to),
+            c.content
+        ));
+    }
+    out
+}
+
+pub fn to_vtt(cues: &[Cue]) -> String {
+    let time = |t: f64| {
+        let (h, m, s, ms) = split_time(t);
+        format!("{:02}:{:02}:{:02}.{:03}", h, m, s, ms)
+    };
+    let mut out = String::from("WEBVTT\n\n");
+    for c in cues {
+        out.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            time(c.from),
+            time(c.to),
+            c.content
+        ));
+    }
+    out
+}
+
+pub fn to_ass(cues: &[Cue], title: &str) -> String {
+    let time = |t: f64| {
+        let (h, m, s, ms) = split_time(t);
+        format!("{}:{:02}:{:02}.{:02}", h, m, s, ms / 10)
+    };
+    let mut out = format!(
+        "[Script Info]\nTitle: {}\nScriptType: v4.00+\nPlayResX: 1920\nPlayResY: 1080\n\n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+         Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, \
+         Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Default,Microsoft YaHei,60,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,\
+         0,0,0,0,100,100,0,0,1,3,0,2,20,20,40,1\n\n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        title
+    );
+    for c in cues {
+        out.push_str(&format!(
+            "Dialogue: 0,{},{},Default,,0,0,0,,{}\n",
+            time(c.from),
+            time(c.to),
+            c.content.replace('\n', "\\N")
+        ));
+    }
+    out
+}
+
+/// B站的语言代码转换为容器使用的ISO 639-2代码，未知时为und
+pub fn iso639_2(lan: &str) -> &'static str {
+    let lan = lan.trim_start_matches("ai-").to_ascii_lowercase();
+    let code = lan.split(['-', '_']).next().unwrap_or("");
+    match code {
+        "zh" => "chi",
+        "en" => "eng",
+        "ja" => "jpn",
+        "ko" => "kor",
+        "es" => "spa",
+        "fr" => "fre",
+        "de" => "ger",
+        "ru" => "rus",
+        "pt" => "por",
+        "it" => "ita",
+        "ar" => "ara",
+        "th" => "tha",
+        "vi" => "vie",
+        "id" => "ind",
+        "ms" => "may",
+        _ => "und",
+    }
+}
+
+pub fn convert(cues: &[Cue], format: SubtitleFormat, title: &str) -> String {
+    match format {
+        SubtitleFormat::Srt => to_srt(cues),
+        SubtitleFormat::Ass => to_ass(cues, title),
+        SubtitleFormat::Vtt => to_vtt(cues),
+    }
+}
+
+#[test]
+fn test_subtitle() {
+    let player: Value = serde_json::from_str(
+        r#"{"data": {"subtitle": {"subtitles": [
+            {"lan": "zh-CN", "lan_doc": "中文（中国）", "subtitle_url": "//aisubtitle.hdslb.com/a.json", "ai_type": 0},
+            {"lan": "ai-zh", "lan_doc": "中文（自动生成）", "subtitle_url": "", "ai_type": 1}
+        ]}}}"#,
+    )
+    .unwrap();
+    let tracks = parse_subtitle_list(&player);
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].url, "https://aisubtitle.hdslb.com/a.json");
+    assert!(!tracks[0].ai);
+    assert_eq!(select(&tracks, &["en-US".to_string()]).len(), 0);
+    assert_eq!(select(&tracks, &[]).len(), 1);
+
+    let json: Value = serde_json::from_str(
+        r#"{"body": [{"from": 0.5, "to": 3661.25, "content": "第一行\n第二行"}]}"#,
+    )
+    .unwrap();
+    let cues = parse_cues(&json);
+    assert_eq!(
+        to_srt(&cues),
+        "1\n00:00:00,500 --> 01:01:01,250\n第一行\n第二行\n\n"
+    );
+    assert_eq!(
+        to_vtt(&cues),
+        "WEBVTT\n\n00:00:00.500 --> 01:01:01.250\n第一行\n第二行\n\n"
+    );
+    assert_eq!(iso639_2("zh-Hant"), "chi");
+    assert_eq!(iso639_2("ai-en"), "eng");
+    assert_eq!(iso639_2("xx"), "und");
+    assert!(to_ass(&cues, "t")
+        .ends_with("Dialogue: 0,0:00:00.50,1:01:01.25,Default,,0,0,0,,第一行\\N第二行\n"));
+}
@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::ffmpeg;
+use crate::remux::{children, find, read_box_header, read_fragmented};
+
+/// 检查下载的m4s能否完整解析，样本数据都在文件内
+pub fn check_stream(path: &str) -> Result<()> {
+    read_fragmented(Path::new(path))
+        .map(|_| ())
+        .with_context(|| format!("Invalid stream {}", path))
+}
+
+/// 读取mp4/m4a中moov/mvhd记录的时长(秒)
+pub fn mp4_duration(path: &Path) -> Result<f64> {
+    let mut input = BufReader::new(File::open(path)?);
+    let len = input.get_ref().metadata()?.len();
+    let mut pos = 0;
+    while pos < len {
+        input.seek(SeekFrom::Start(pos))?;
+        let (kind, header_len, size) = read_box_header(&mut input, pos, len)?;
+        if &kind == b"moov" {
+            let mut body = vec![0u8; (size - header_len) as usize];
+            input.read_exact(&mut body)?;
+            let boxes = children(&body)?;
+            let mvhd = find(&boxes, b"mvhd").context("Missing mvhd box")?.body;
+            // version 1时创建/修改时间和时长为64位
+            let (timescale, duration) = if mvhd.first() == Some(&1) && mvhd.len() >= 32 {
+                (
+                    u32::from_be_bytes(mvhd[20..24].try_into()?),
+                    u64::from_be_bytes(mvhd[24..32].try_into()?),
+                )
+            } else if mvhd.len() >= 20 {
+                (
+                    u32::from_be_bytes(mvhd[12..16].try_into()?),
+                    u32::from_be_bytes(mvhd[16..20].try_into()?) as u64,
+                )
+            } else {
+                return Err(anyhow!("Invalid mvhd box"));
+            };
+            return Ok(duration as f64 / timescale.max(1) as f64);
+        }
+        pos += size;
+    }
+    Err(anyhow!("No moov box in {}", path.display()))
+}
+
+/// 输出文件的时长(秒)，mp4/m4a直接解析，其他格式使用ffmpeg读取
+pub async fn output_duration(path: &str, config: &Config) -> Result<f64> {
+    if path.ends_with(".mp4") || path.ends_with(".m4a") {
+        mp4_duration(Path::new(path))
+    } else {
+        ffmpeg::probe_duration(&config.ffmpeg_path, path).await
+    }
+}
+
+/// 检查输出时长与接口返回的时长是否一致，expected为0(未知)时跳过
+pub async fn check_duration(path: &str, expected: u64, config: &Config) -> Result<()> {
+    if expected == 0 {
+        return Ok(());
+    }
+    let actual = output_duration(path, config).await?;
+    if !duration_matches(actual, expected) {
+        return Err(anyhow!(
+            "Duration of {} is {:.1}s, expected {}s",
+            path,
+            actual,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// 接口的时长为整数秒，允许2秒或1%的误差
+fn duration_matches(actual: f64, expected: u64) -> bool {
+    let expected = expected as f64;
+    (actual - expected).abs() <= (expected * 0.01).max(2.0)
+}
+
+#[test]
+fn test_duration_matches() {
+    assert!(duration_matches(119.5, 120));
+    assert!(duration_matches(3590.0, 3600));
+    assert!(!duration_matches(60.0, 120));
+}
+
+#[test]
+fn test_mp4_duration() {
+    use crate::metadata::Metadata;
+    use crate::remux::{remux, test_fragmented};
+
+    let dir = std::env::temp_dir();
+    let video = dir.join("verify_test_video.m4s");
+    let output = dir.join("verify_test.mp4");
+    std::fs::write(&video, test_fragmented(&[b"vvvv", b"ww"], 1000)).unwrap();
+    check_stream(video.to_str().unwrap()).unwrap();
+    remux(&[video.as_path()], &output, &Metadata::default(), None).unwrap();
+    assert!((mp4_duration(&output).unwrap() - 2.048).abs() < 0.001);
+
+    // 截断的流无法通过检查
+    let data = std::fs::read(&video).unwrap();
+    std::fs::write(&video, &data[..data.len() - 1]).unwrap();
+    assert!(check_stream(video.to_str().unwrap()).is_err());
+    for p in [video, output] {
+        std::fs::remove_file(p).unwrap();
+    }
+}